@@ -0,0 +1,65 @@
+use std::io::{sink, Cursor};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use bfk::*;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("hello", "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++."),
+    ("nested_loops", "++++++++[->++++++++[->++++++++[->+<]<]<]"),
+    ("count_down", "-[>-[-]<-]"),
+];
+
+fn run_plain(code: &Code<Op>) -> u64 {
+    let mut data = vec![0u8; 1024];
+    let mut input = Cursor::new(Vec::new());
+    let mut output = sink();
+    let mut env = Environment::new(&mut data, &mut input, &mut output);
+    run(code, &mut env).unwrap();
+    env.steps()
+}
+
+fn run_compressed(code: &Code<CompressedOp>) {
+    let mut data = vec![0u8; 1024];
+    let mut input = Cursor::new(Vec::new());
+    let mut output = sink();
+    let mut env = Environment::new(&mut data, &mut input, &mut output);
+    run(code, &mut env).unwrap();
+}
+
+fn interpreters(c: &mut Criterion) {
+    let language = Language::default();
+
+    for &(name, source) in PROGRAMS {
+        let ops = parse(source, &language);
+        let compressed = compress(&ops);
+
+        let mut group = c.benchmark_group(name);
+        // Throughput in steps of the plain code, so both are comparable
+        group.throughput(Throughput::Elements(run_plain(&ops)));
+        group.bench_function("plain", |b| b.iter(|| run_plain(&ops)));
+        group.bench_function("compressed", |b| b.iter(|| run_compressed(&compressed)));
+        group.finish();
+    }
+}
+
+/// Run code over a growable tape starting with a single cell
+fn run_on_tape<T: Tape>(code: &Code<Op>, mut tape: T) {
+    let mut input = Cursor::new(Vec::new());
+    let mut output = sink();
+    let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+    run(code, &mut env).unwrap();
+}
+
+fn tapes(c: &mut Criterion) {
+    // Every step left grows the tape at its front
+    let ops = parse(&format!("+{}", "<+".repeat(10_000)), &Language::default());
+
+    let mut group = c.benchmark_group("grow_front");
+    group.bench_function("vec", |b| b.iter(|| run_on_tape(&ops, BiTape::<u8>::new(1))));
+    group.bench_function("deque", |b| b.iter(|| run_on_tape(&ops, DequeTape::<u8>::new(1))));
+    group.finish();
+}
+
+criterion_group!(benches, interpreters, tapes);
+criterion_main!(benches);
@@ -0,0 +1,424 @@
+//! Structural analyses of code.
+
+use std::fmt::{self, Write};
+use std::io::{self, Read};
+use std::ops::Range;
+
+use crate::{run, BiTape, Bracket, Code, CompressedOp, Environment, RunOptions};
+
+/// Loop in code.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LoopSpan {
+    /// Program counter of the loop start
+    pub start: usize,
+    /// Program counter of the loop end
+    pub end: usize,
+    /// Index of the innermost enclosing loop in the spans, if any
+    pub parent: Option<usize>,
+}
+
+/// Straight-line run of operations in code, only ever entered at its start.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BasicBlock {
+    /// Program counters of the operations, the last of which may be a loop bracket
+    pub pcs: Range<usize>,
+    /// Indices of the blocks control may go to next, falling through first
+    pub successors: Vec<usize>,
+}
+
+/// Suspicious code found by `Code::sanity_check`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Warning {
+    /// Single pointer move at the pc going further than the whole tape.
+    MoveExceedsTape { pc: usize, distance: usize, tape_size: usize },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::MoveExceedsTape { pc, distance, tape_size } =>
+                write!(f, "pc {}: moving the pointer by {} exceeds the tape size {}", pc, distance, tape_size),
+        }
+    }
+}
+
+impl std::error::Error for Warning {}
+
+impl<T: Bracket> Code<T> {
+    /// Operations split into basic blocks ordered by pc. A block ends at each loop
+    /// bracket, which goes on to the next block or the one its jump lands on. The
+    /// end of an if body is taken to jump back like a loop end, which it never does.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let len = self.ops.len();
+        let mut leaders = vec![false; len + 1];
+        leaders[0] = true;
+        for (pc, op) in self.ops.iter().enumerate() {
+            if op.is_loop_start() || op.is_loop_end() {
+                leaders[pc + 1] = true;
+                if let Some(leader) = leaders.get_mut(self.jump_table[pc]) {
+                    *leader = true;
+                }
+            }
+        }
+        let starts = (0..len).filter(|&pc| leaders[pc]).collect::<Vec<_>>();
+
+        starts.iter()
+            .enumerate()
+            .map(|(index, &start)| {
+                let end = starts.get(index + 1).copied().unwrap_or(len);
+                let mut successors = Vec::new();
+                if end < len {
+                    successors.push(index + 1);
+                }
+                let last = &self.ops[end - 1];
+                if last.is_loop_start() || last.is_loop_end() {
+                    if let Ok(target) = starts.binary_search(&self.jump_table[end - 1]) {
+                        if !successors.contains(&target) {
+                            successors.push(target);
+                        }
+                    }
+                }
+                BasicBlock { pcs: start..end, successors }
+            })
+            .collect()
+    }
+
+    /// Every loop ordered by its start. Unmatched brackets are ignored.
+    pub fn loops(&self) -> Vec<LoopSpan> {
+        let mut spans: Vec<LoopSpan> = Vec::new();
+        // Indices of spans whose end is not reached yet
+        let mut open: Vec<usize> = Vec::new();
+
+        for (pc, op) in self.ops.iter().enumerate() {
+            if op.is_loop_start() {
+                // Loops which never end have no jump past their start
+                if let Some(end) = self.jump_table[pc].checked_sub(1).filter(|&end| end > pc) {
+                    spans.push(LoopSpan { start: pc, end, parent: open.last().copied() });
+                    open.push(spans.len() - 1);
+                }
+            } else if open.last().is_some_and(|&index| spans[index].end == pc) {
+                open.pop();
+            }
+        }
+
+        spans
+    }
+
+    /// Depth of the most deeply nested loop, 0 without loops. Unmatched loop ends are ignored.
+    pub fn max_loop_depth(&self) -> usize {
+        let mut depth = 0usize;
+        let mut max = 0;
+        for op in self.ops.iter() {
+            if op.is_loop_start() {
+                depth += 1;
+                max = max.max(depth);
+            } else if op.is_loop_end() {
+                depth = depth.saturating_sub(1);
+            }
+        }
+        max
+    }
+
+    /// Graphviz graph of the control flow between loops and the straight-line
+    /// blocks of operations between brackets. Dashed edges jump back to loop starts.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph code {\n");
+        fn edge(dot: &mut String, from: &str, to: &str, back: bool) {
+            let style = if back { " [style=dashed]" } else { "" };
+            writeln!(dot, "    {} -> {}{};", from, to, style).expect("Writing to string never fails");
+        }
+
+        // Node control flows from, and starts of the loops it is in
+        let mut prev: Option<String> = None;
+        let mut open = Vec::new();
+        let mut pc = 0;
+        while pc < self.ops.len() {
+            let op = &self.ops[pc];
+            let node = if op.is_loop_start() {
+                let node = format!("loop_{}", pc);
+                writeln!(dot, "    {} [shape=diamond, label=\"loop at {}\"];", node, pc).expect("Writing to string never fails");
+                open.push(node.clone());
+                pc += 1;
+                node
+            } else if op.is_loop_end() {
+                if let Some(start) = open.pop() {
+                    edge(&mut dot, prev.as_deref().unwrap_or(&start), &start, true);
+                    prev = Some(start);
+                }
+                pc += 1;
+                continue;
+            } else {
+                let first = pc;
+                while pc < self.ops.len() && !self.ops[pc].is_loop_start() && !self.ops[pc].is_loop_end() {
+                    pc += 1;
+                }
+                let node = format!("block_{}", first);
+                writeln!(dot, "    {} [shape=box, label=\"pc {}-{}\"];", node, first, pc - 1).expect("Writing to string never fails");
+                node
+            };
+            if let Some(prev) = &prev {
+                edge(&mut dot, prev, &node, false);
+            }
+            prev = Some(node);
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Most operations `Code::constant_output_prefix` runs looking for output
+const MAX_PREFIX_STEPS: u64 = 1_000_000;
+
+/// Reader failing every read, stopping a run at the first input
+struct NoInput;
+
+impl Read for NoInput {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("no input"))
+    }
+}
+
+impl Code<CompressedOp> {
+    /// Largest pointer the code can reach from the start of the tape, if it is
+    /// known without running: every loop must leave the pointer where it found it,
+    /// and the pointer must never move before the start.
+    pub fn max_static_offset(&self) -> Option<usize> {
+        let mut offset = 0usize;
+        let mut max = 0;
+        let mut loop_offsets = Vec::new();
+
+        for op in self.ops.iter() {
+            match *op {
+                CompressedOp::Forward(n) => {
+                    offset = offset.checked_add(n)?;
+                    max = max.max(offset);
+                }
+                CompressedOp::Back(n) => offset = offset.checked_sub(n)?,
+                _ if op.is_loop_start() => loop_offsets.push(offset),
+                _ if op.is_loop_end() => match loop_offsets.pop() {
+                    Some(start) if start != offset => return None,
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        Some(max)
+    }
+
+    /// Output the code writes from a fresh tape before it first reads input, which
+    /// is the same on every run. Looking stops after a bounded number of steps for
+    /// code that never reads.
+    pub fn constant_output_prefix(&self) -> Vec<u8> {
+        let mut tape = BiTape::<u8>::new(1);
+        let mut input = NoInput;
+        let mut output = Vec::new();
+        let options = RunOptions::builder().step_limit(Some(MAX_PREFIX_STEPS)).build();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, options);
+        // Stopping on input or the step limit leaves what was written before
+        let _ = run(self, &mut env);
+        output
+    }
+
+    /// Program counters of the operations of loop bodies that never run, as the
+    /// loop starts right after clearing the cell or leaving a loop on it. The loop
+    /// ends are included, while the loop starts still run.
+    pub fn find_unreachable_ops(&self) -> Vec<usize> {
+        let mut unreachable = Vec::new();
+        let mut pc = 1;
+        while pc < self.ops.len() {
+            let cleared = matches!(self.ops[pc - 1], CompressedOp::SetZero | CompressedOp::LoopEnd);
+            if cleared && self.ops[pc].is_loop_start() {
+                // Loop starts jump past their end
+                let after = self.jump_table[pc];
+                unreachable.extend(pc + 1..after);
+                pc = after;
+            } else {
+                pc += 1;
+            }
+        }
+        unreachable
+    }
+
+    /// Fail on the first operation that can't make sense on a tape of the size, so
+    /// hosts can reject code like `Forward(usize::MAX)` before running it
+    pub fn sanity_check(&self, tape_size: usize) -> Result<(), Warning> {
+        for (pc, op) in self.ops.iter().enumerate() {
+            match *op {
+                CompressedOp::Forward(distance) | CompressedOp::Back(distance) if distance > tape_size =>
+                    return Err(Warning::MoveExceedsTape { pc, distance, tape_size }),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Spare cells `suggested_tape_size` adds past the largest static offset
+const TAPE_MARGIN: usize = 16;
+
+/// Tape size fitting the code with a margin when its largest pointer is known
+/// statically, otherwise the default. Never more than the default.
+pub fn suggested_tape_size(code: &Code<CompressedOp>, default: usize) -> usize {
+    match code.max_static_offset() {
+        Some(max) => max.saturating_add(1 + TAPE_MARGIN).min(default),
+        None => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{assemble, compress, decode_bytecode, disassemble, encode_bytecode, fold_clear_loops, fold_multiply_loops, optimize_with_events, parse, run,
+                Environment, Language};
+
+    #[test]
+    fn test_loops() {
+        let code = parse("[[]][]", &Language::default());
+
+        assert_eq!(code.loops(), [
+            LoopSpan { start: 0, end: 3, parent: None },
+            LoopSpan { start: 1, end: 2, parent: Some(0) },
+            LoopSpan { start: 4, end: 5, parent: None },
+        ]);
+
+        let code = parse("[[]", &Language::default());
+        assert_eq!(code.loops(), [LoopSpan { start: 1, end: 2, parent: None }]);
+    }
+
+    #[test]
+    fn test_basic_blocks() {
+        let code = compress(&parse("+[>[-]<-].", &Language::default()));
+        let block = |pcs: Range<usize>, successors: &[usize]| BasicBlock { pcs, successors: successors.to_vec() };
+
+        assert_eq!(code.basic_blocks(), [
+            block(0..2, &[1, 4]),
+            block(2..4, &[2, 3]),
+            block(4..6, &[3, 2]),
+            block(6..9, &[4, 1]),
+            block(9..10, &[]),
+        ]);
+        assert_eq!(parse("+-", &Language::default()).basic_blocks(), [block(0..2, &[])]);
+        assert_eq!(parse("", &Language::default()).basic_blocks(), []);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let code = compress(&parse("++[>+[-]<-]>[.]", &Language::default()));
+        let dot = code.to_dot();
+
+        assert!(dot.starts_with("digraph code {\n"));
+        assert_eq!(dot.matches("shape=diamond").count(), 3);
+        assert_eq!(dot.matches("shape=box").count(), 6);
+        assert!(dot.contains("    block_2 [shape=box, label=\"pc 2-3\"];\n"));
+        assert!(dot.contains("    block_0 -> loop_1;\n"));
+        assert!(dot.contains("    loop_1 -> block_2;\n"));
+        assert!(dot.contains("    block_2 -> loop_4;\n"));
+        assert!(dot.contains("    block_5 -> loop_4 [style=dashed];\n"));
+        assert!(dot.contains("    loop_4 -> block_7;\n"));
+        assert!(dot.contains("    block_7 -> loop_1 [style=dashed];\n"));
+        assert!(dot.contains("    loop_1 -> block_10;\n"));
+        assert!(dot.contains("    block_12 -> loop_11 [style=dashed];\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_max_static_offset() {
+        let language = Language::default();
+        let offset = |source| compress(&parse(source, &language)).max_static_offset();
+
+        assert_eq!(offset(""), Some(0));
+        assert_eq!(offset(">>>.<<>"), Some(3));
+        assert_eq!(offset("+[>>+<<-]>>[>+++>[-]<<-]<"), Some(4));
+        assert_eq!(offset("+[>]"), None);
+        assert_eq!(offset("+[<+>>]"), None);
+        assert_eq!(offset("<>>"), None);
+
+        assert_eq!(suggested_tape_size(&compress(&parse("++[>+++<-]>.", &language)), 1 << 20), 18);
+        assert_eq!(suggested_tape_size(&compress(&parse("+[>+]", &language)), 1 << 20), 1 << 20);
+
+        let code = Code { ops: vec![CompressedOp::Forward(1 << 40), CompressedOp::PutChar], jump_table: vec![0; 2], source_map: None };
+        assert_eq!(suggested_tape_size(&code, 1 << 20), 1 << 20);
+    }
+
+    #[test]
+    fn test_sanity_check() {
+        let code = compress(&parse(">>>>+<<<<", &Language::default()));
+        assert_eq!(code.sanity_check(4), Ok(()));
+        assert_eq!(code.sanity_check(3), Err(Warning::MoveExceedsTape { pc: 0, distance: 4, tape_size: 3 }));
+
+        let code = Code { ops: vec![CompressedOp::Add(1), CompressedOp::Back(usize::MAX)], jump_table: vec![0; 2], source_map: None };
+        assert_eq!(code.sanity_check(1024), Err(Warning::MoveExceedsTape { pc: 1, distance: usize::MAX, tape_size: 1024 }));
+    }
+
+    #[test]
+    fn test_constant_output_prefix() {
+        let language = Language::default();
+        let code = compress(&parse("++++++++[>+++++++++<-]>.<+++++[>+++++++<-]>--.,[.,]", &language));
+        assert_eq!(code.constant_output_prefix(), b"Hi");
+
+        assert_eq!(compress(&parse(",.", &language)).constant_output_prefix(), b"");
+        // Looping forever without reading stops at the step limit
+        assert_eq!(compress(&parse("+.[]", &language)).constant_output_prefix(), [1]);
+    }
+
+    #[test]
+    fn test_find_unreachable_ops() {
+        let language = Language::default();
+        assert_eq!(compress(&parse("[-][+++]", &language)).find_unreachable_ops(), [4, 5]);
+        assert_eq!(fold_clear_loops(&compress(&parse("[-][+++]", &language))).find_unreachable_ops(), [2, 3]);
+        assert_eq!(compress(&parse(",[>][[-]+].", &language)).find_unreachable_ops(), [5, 6, 7, 8, 9]);
+        assert_eq!(compress(&parse("[-]>[+++]", &language)).find_unreachable_ops(), []);
+    }
+
+    #[test]
+    fn test_max_loop_depth() {
+        let language = Language::default();
+
+        assert_eq!(parse("+-", &language).max_loop_depth(), 0);
+        assert_eq!(parse("[][[[]][]]", &language).max_loop_depth(), 3);
+        assert_eq!(compress(&parse("+[>[-]<-]", &language)).max_loop_depth(), 2);
+    }
+
+    #[test]
+    fn test_deep_nesting() {
+        // Deep enough to overflow the stack of a test thread if anything recursed per loop
+        const DEPTH: usize = 100_000;
+        let source = format!("+{}-{}>", "[".repeat(DEPTH), "]".repeat(DEPTH));
+        let language = Language::default();
+
+        let code = parse(&source, &language);
+        assert_eq!(code.max_loop_depth(), DEPTH);
+        assert_eq!(code.loops().len(), DEPTH);
+        assert_eq!(code.loops()[DEPTH - 1].parent, Some(DEPTH - 2));
+        assert!(code.to_dot().contains(&format!("loop_{}", DEPTH)));
+
+        let compressed = compress(&code);
+        assert!(compressed.validate().is_ok());
+        assert_eq!(compressed.max_static_offset(), Some(1));
+        let (optimized, _) = optimize_with_events(&compressed);
+        let optimized = fold_multiply_loops(&optimized);
+        assert_eq!(optimized.max_loop_depth(), DEPTH - 1);
+
+        let decoded = decode_bytecode(&encode_bytecode(&optimized)).unwrap();
+        assert_eq!(assemble(&disassemble(&decoded)).unwrap(), decoded);
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&optimized, &mut env).unwrap();
+        assert_eq!(env.pointer(), 1);
+    }
+
+    #[test]
+    fn test_loops_compressed() {
+        let code = compress(&parse("++[>>[-]<<-]", &Language::default()));
+
+        assert_eq!(code.loops(), [
+            LoopSpan { start: 1, end: 8, parent: None },
+            LoopSpan { start: 3, end: 5, parent: Some(0) },
+        ]);
+    }
+}
@@ -0,0 +1,199 @@
+//! Textual listing of compressed code, one op per line.
+//!
+//! Mnemonics are `ADD n`, `SUB n`, `BACK n`, `FWD n`, `PUT`, `GET`, `LOOP`, `END`, `ZERO`, `SET n`, `ASSERT`, `IF`, `ENDIF` and `PTR`.
+//! Blank lines and anything after `;` are ignored.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{Code, CompressedOp};
+
+/// Errors occurring while assembling.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum AsmError {
+    /// Unknown mnemonic.
+    UnknownMnemonic { line: usize, mnemonic: String },
+    /// Operand missing, not a number or given to an op without one.
+    BadOperand { line: usize },
+    /// `LOOP` without `END`, or `IF` without `ENDIF`.
+    UnmatchedLoop { line: usize },
+    /// `END` without `LOOP`, or `ENDIF` without `IF`.
+    UnmatchedEnd { line: usize },
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic { line, mnemonic } => write!(f, "unknown mnemonic {:?} on line {}", mnemonic, line),
+            AsmError::BadOperand { line } => write!(f, "bad operand on line {}", line),
+            AsmError::UnmatchedLoop { line } => write!(f, "unmatched LOOP or IF on line {}", line),
+            AsmError::UnmatchedEnd { line } => write!(f, "unmatched END or ENDIF on line {}", line),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Assemble a listing into compressed code. Lines are numbered from 1 in errors.
+pub fn assemble(text: &str) -> Result<Code<CompressedOp>, AsmError> {
+    let mut ops = Vec::new();
+    let mut jump_table = Vec::new();
+    let mut map_stack = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line_number = i + 1;
+        let mut words = line.split(';').next().unwrap_or("").split_whitespace();
+        let mnemonic = match words.next() {
+            Some(mnemonic) => mnemonic,
+            None => continue,
+        };
+        let operand = words.next();
+        if words.next().is_some() {
+            return Err(AsmError::BadOperand { line: line_number });
+        }
+
+        fn count<N: FromStr>(operand: Option<&str>, line: usize) -> Result<N, AsmError> {
+            operand.and_then(|s| s.parse().ok()).ok_or(AsmError::BadOperand { line })
+        }
+        let none = || -> Result<(), AsmError> {
+            if operand.is_some() { Err(AsmError::BadOperand { line: line_number }) } else { Ok(()) }
+        };
+
+        let pc = ops.len();
+        let op = match mnemonic {
+            "ADD" => CompressedOp::Add(count(operand, line_number)?),
+            "SUB" => CompressedOp::Sub(count(operand, line_number)?),
+            "BACK" => CompressedOp::Back(count(operand, line_number)?),
+            "FWD" => CompressedOp::Forward(count(operand, line_number)?),
+            "PUT" => none().map(|_| CompressedOp::PutChar)?,
+            "GET" => none().map(|_| CompressedOp::GetChar)?,
+            "ZERO" => none().map(|_| CompressedOp::SetZero)?,
+            "SET" => CompressedOp::SetValue(count(operand, line_number)?),
+            "ASSERT" => none().map(|_| CompressedOp::Assert)?,
+            "PTR" => none().map(|_| CompressedOp::PutPointer)?,
+            "LOOP" | "IF" => {
+                none()?;
+                let start = if mnemonic == "IF" { CompressedOp::IfNonzero } else { CompressedOp::LoopStart };
+                map_stack.push((pc, line_number, start));
+                start
+            }
+            "END" | "ENDIF" => {
+                none()?;
+                let (start, end) = if mnemonic == "ENDIF" {
+                    (CompressedOp::IfNonzero, CompressedOp::EndIf)
+                } else {
+                    (CompressedOp::LoopStart, CompressedOp::LoopEnd)
+                };
+                let begin = match map_stack.pop() {
+                    Some((begin, _, op)) if op == start => begin,
+                    _ => return Err(AsmError::UnmatchedEnd { line: line_number }),
+                };
+                jump_table[begin] = pc + 1;
+                jump_table.push(begin + 1);
+                ops.push(end);
+                continue;
+            }
+            _ => return Err(AsmError::UnknownMnemonic { line: line_number, mnemonic: mnemonic.to_owned() }),
+        };
+        ops.push(op);
+        jump_table.push(0);
+    }
+
+    if let Some(&(_, line, _)) = map_stack.last() {
+        return Err(AsmError::UnmatchedLoop { line });
+    }
+
+    Ok(Code { ops, jump_table, source_map: None })
+}
+
+/// Write compressed code as a listing which `assemble` reads back
+pub fn disassemble(code: &Code<CompressedOp>) -> String {
+    let mut text = String::new();
+    for op in code.ops.iter() {
+        let line = match *op {
+            CompressedOp::Add(n) => format!("ADD {}", n),
+            CompressedOp::Sub(n) => format!("SUB {}", n),
+            CompressedOp::Back(n) => format!("BACK {}", n),
+            CompressedOp::Forward(n) => format!("FWD {}", n),
+            CompressedOp::PutChar => "PUT".to_owned(),
+            CompressedOp::GetChar => "GET".to_owned(),
+            CompressedOp::LoopStart => "LOOP".to_owned(),
+            CompressedOp::LoopEnd => "END".to_owned(),
+            CompressedOp::SetZero => "ZERO".to_owned(),
+            CompressedOp::SetValue(n) => format!("SET {}", n),
+            CompressedOp::Assert => "ASSERT".to_owned(),
+            CompressedOp::PutPointer => "PTR".to_owned(),
+            CompressedOp::IfNonzero => "IF".to_owned(),
+            CompressedOp::EndIf => "ENDIF".to_owned(),
+        };
+        text.push_str(&line);
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, run, Environment, Language};
+
+    #[test]
+    fn test_assemble_run() {
+        let text = "\
+            ; 'A' is 8 * 8 + 1
+            ADD 8
+            LOOP
+              FWD 1
+              ADD 8
+              BACK 1
+              SUB 1
+            END
+            FWD 1
+            ADD 1
+            PUT
+        ";
+        let code = assemble(text).unwrap();
+        assert!(code.validate().is_ok());
+        assert_eq!(code.jump_table[1], 7);
+        assert_eq!(code.jump_table[6], 2);
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let code = compress(&parse("++[->+++<]>.,", &Language::default()));
+        let text = disassemble(&code);
+        assert_eq!(text, "ADD 2\nLOOP\nSUB 1\nFWD 1\nADD 3\nBACK 1\nEND\nFWD 1\nPUT\nGET\n");
+
+        let assembled = assemble(&text).unwrap();
+        assert_eq!(assembled.ops, code.ops);
+        assert_eq!(assembled.jump_table, code.jump_table);
+
+        let set = assemble("SET 3").unwrap();
+        assert_eq!(set.ops, [CompressedOp::SetValue(3)]);
+        assert_eq!(disassemble(&set), "SET 3\n");
+
+        let put_pointer = assemble("PTR").unwrap();
+        assert_eq!(put_pointer.ops, [CompressedOp::PutPointer]);
+        assert_eq!(disassemble(&put_pointer), "PTR\n");
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        assert_eq!(assemble("ADD 1\nJMP 3").err(), Some(AsmError::UnknownMnemonic { line: 2, mnemonic: "JMP".to_owned() }));
+        assert_eq!(assemble("ADD").err(), Some(AsmError::BadOperand { line: 1 }));
+        assert_eq!(assemble("ADD x").err(), Some(AsmError::BadOperand { line: 1 }));
+        assert_eq!(assemble("PUT 1").err(), Some(AsmError::BadOperand { line: 1 }));
+        assert_eq!(assemble("LOOP\n\nLOOP\nEND").err(), Some(AsmError::UnmatchedLoop { line: 1 }));
+        assert_eq!(assemble("END").err(), Some(AsmError::UnmatchedEnd { line: 1 }));
+        assert_eq!(assemble("IF\nEND").err(), Some(AsmError::UnmatchedEnd { line: 2 }));
+    }
+}
@@ -0,0 +1,61 @@
+//! Measuring how fast programs run.
+
+use std::io::{sink, Cursor};
+use std::time::{Duration, Instant};
+
+use crate::{compress, parse, run, Environment, Language, RunOptions};
+
+/// Result of running a program repeatedly.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct BenchResult {
+    /// Number of times the program ran
+    pub iterations: u64,
+    /// Operations run over all iterations
+    pub steps: u64,
+    /// Time spent running, excluding parsing and compressing
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Operations run per second
+    pub fn steps_per_sec(&self) -> f64 {
+        self.steps as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Run compressed source `iterations` times over a fresh tape with no input,
+/// discarding output. Panics if the program fails at runtime.
+pub fn bench_program(source: &str, iterations: u64) -> BenchResult {
+    let code = compress(&parse(source, &Language::default()));
+    let options = RunOptions::default();
+    let mut data = vec![0u8; options.tape_size()];
+
+    let mut steps = 0;
+    let mut elapsed = Duration::ZERO;
+    for _ in 0..iterations {
+        data.iter_mut().for_each(|cell| *cell = 0);
+        let mut input = Cursor::new(Vec::new());
+        let mut output = sink();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+
+        let start = Instant::now();
+        run(&code, &mut env).expect("Program failed while benchmarking");
+        elapsed += start.elapsed();
+        steps += env.steps();
+    }
+
+    BenchResult { iterations, steps, elapsed }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bench_program() {
+        let result = bench_program("++[->+++<]>.", 3);
+        assert_eq!(result.iterations, 3);
+        assert_eq!(result.steps, 3 * 14);
+        assert!(result.steps_per_sec() > 0.0);
+    }
+}
@@ -0,0 +1,321 @@
+//! Compact binary format of compressed code.
+//!
+//! All integers are little-endian. The layout is:
+//!
+//! - magic `b"BFKC"`
+//! - version (`u8`)
+//! - width in bytes (`u8`) of the cells the code was compressed for, 1, 2 or 4
+//! - op count (`u64`)
+//! - ops, each a tag (`u8`) followed by an operand (`u64`), which is zero for ops without one
+//! - jump table, an entry (`u64`) for each op
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+
+use crate::{optimize, optimize_with_events, parse_compressed, Cell, Code, CompressedOp, Language, ParseError, ValidationError};
+
+const MAGIC: &[u8; 4] = b"BFKC";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 4 + 1 + 1 + 8;
+const OP_SIZE: usize = 1 + 8;
+const JUMP_SIZE: usize = 8;
+
+const TAG_ADD: u8 = 0;
+const TAG_SUB: u8 = 1;
+const TAG_BACK: u8 = 2;
+const TAG_FORWARD: u8 = 3;
+const TAG_PUT_CHAR: u8 = 4;
+const TAG_GET_CHAR: u8 = 5;
+const TAG_LOOP_START: u8 = 6;
+const TAG_LOOP_END: u8 = 7;
+const TAG_SET_ZERO: u8 = 8;
+const TAG_ASSERT: u8 = 9;
+const TAG_IF_NONZERO: u8 = 10;
+const TAG_END_IF: u8 = 11;
+const TAG_SET_VALUE: u8 = 12;
+const TAG_PUT_POINTER: u8 = 13;
+
+/// Errors occurring while decoding bytecode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum DecodeError {
+    /// Doesn't start with the bytecode magic.
+    BadMagic,
+    /// Encoded with a version this decoder doesn't know.
+    UnsupportedVersion(u8),
+    /// Encoded for cells of a width this decoder doesn't know.
+    UnsupportedCellWidth(u8),
+    /// Encoded for cells of a different width in bytes than decoding for.
+    WrongCellWidth { expected: u8, actual: u8 },
+    /// Length doesn't match the op count in the header.
+    BadLength { expected: usize, actual: usize },
+    /// Op with an unknown tag or an operand out of range.
+    BadOp { index: usize },
+    /// Decoded code isn't runnable.
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a bytecode"),
+            DecodeError::UnsupportedVersion(version) => write!(f, "unsupported bytecode version {}", version),
+            DecodeError::UnsupportedCellWidth(width) => write!(f, "unsupported cell width {}", width),
+            DecodeError::WrongCellWidth { expected, actual } =>
+                write!(f, "bytecode is for {} byte cells, not {} byte cells", actual, expected),
+            DecodeError::BadLength { expected, actual } =>
+                write!(f, "bytecode must be {} bytes but is {} bytes", expected, actual),
+            DecodeError::BadOp { index } => write!(f, "bad op at index {}", index),
+            DecodeError::Invalid(err) => write!(f, "invalid code: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Encode compressed code for `u8` cells into bytecode
+pub fn encode_bytecode(code: &Code<CompressedOp>) -> Vec<u8> {
+    encode_bytecode_for::<u8>(code)
+}
+
+/// Encode code compressed for cells of type `C` into bytecode, recording their width
+pub fn encode_bytecode_for<C: Cell>(code: &Code<CompressedOp>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + code.ops.len() * (OP_SIZE + JUMP_SIZE));
+
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(VERSION);
+    bytes.push((C::BITS / 8) as u8);
+    bytes.extend_from_slice(&(code.ops.len() as u64).to_le_bytes());
+
+    for op in code.ops.iter() {
+        let (tag, operand) = match *op {
+            CompressedOp::Add(n) => (TAG_ADD, n as u64),
+            CompressedOp::Sub(n) => (TAG_SUB, n as u64),
+            CompressedOp::Back(n) => (TAG_BACK, n as u64),
+            CompressedOp::Forward(n) => (TAG_FORWARD, n as u64),
+            CompressedOp::PutChar => (TAG_PUT_CHAR, 0),
+            CompressedOp::GetChar => (TAG_GET_CHAR, 0),
+            CompressedOp::LoopStart => (TAG_LOOP_START, 0),
+            CompressedOp::LoopEnd => (TAG_LOOP_END, 0),
+            CompressedOp::SetZero => (TAG_SET_ZERO, 0),
+            CompressedOp::SetValue(n) => (TAG_SET_VALUE, n as u64),
+            CompressedOp::Assert => (TAG_ASSERT, 0),
+            CompressedOp::IfNonzero => (TAG_IF_NONZERO, 0),
+            CompressedOp::EndIf => (TAG_END_IF, 0),
+            CompressedOp::PutPointer => (TAG_PUT_POINTER, 0),
+        };
+        bytes.push(tag);
+        bytes.extend_from_slice(&operand.to_le_bytes());
+    }
+
+    for &jump in code.jump_table.iter() {
+        bytes.extend_from_slice(&(jump as u64).to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Whether the bytes start with the bytecode magic, so they're bytecode rather than source
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Parse source into compressed code, optimize it and encode it into bytecode.
+/// Level 0 only compresses, 1 runs the optimizations once and 2 or more runs them
+/// until nothing changes.
+pub fn compile_to_bytecode(source: &str, language: &Language, opt_level: u8) -> Result<Vec<u8>, ParseError> {
+    let code = parse_compressed(source, language)?;
+    let code = match opt_level {
+        0 => code,
+        1 => optimize_with_events(&code).0,
+        _ => optimize(&code),
+    };
+    Ok(encode_bytecode(&code))
+}
+
+/// Decode bytecode for `u8` cells into compressed code, validating it's runnable
+pub fn decode_bytecode(bytes: &[u8]) -> Result<Code<CompressedOp>, DecodeError> {
+    decode_bytecode_for::<u8>(bytes)
+}
+
+/// Decode bytecode into code compressed for cells of type `C`, validating it's
+/// runnable and failing if it was encoded for cells of another width
+pub fn decode_bytecode_for<C: Cell>(bytes: &[u8]) -> Result<Code<CompressedOp>, DecodeError> {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    if bytes[4] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(bytes[4]));
+    }
+    let expected = (C::BITS / 8) as u8;
+    match bytes[5] {
+        width if width == expected => {}
+        1 | 2 | 4 => return Err(DecodeError::WrongCellWidth { expected, actual: bytes[5] }),
+        width => return Err(DecodeError::UnsupportedCellWidth(width)),
+    }
+    let cell_max = C::MAX.to_u32();
+
+    let op_count = usize::try_from(read_u64(&bytes[6..HEADER_SIZE])).unwrap_or(usize::MAX);
+    let expected = op_count
+        .checked_mul(OP_SIZE + JUMP_SIZE)
+        .and_then(|size| size.checked_add(HEADER_SIZE))
+        .unwrap_or(usize::MAX);
+    if bytes.len() != expected {
+        return Err(DecodeError::BadLength { expected, actual: bytes.len() });
+    }
+
+    let ops_end = HEADER_SIZE + op_count * OP_SIZE;
+    let ops = bytes[HEADER_SIZE..ops_end]
+        .chunks(OP_SIZE)
+        .enumerate()
+        .map(|(index, chunk)| decode_op(chunk[0], read_u64(&chunk[1..]), cell_max).ok_or(DecodeError::BadOp { index }))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let jump_table = bytes[ops_end..]
+        .chunks(JUMP_SIZE)
+        .map(|chunk| read_u64(chunk) as usize)
+        .collect();
+
+    let code = Code { ops, jump_table, source_map: None };
+    code.validate().map_err(DecodeError::Invalid)?;
+    Ok(code)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("8 bytes"))
+}
+
+fn decode_op(tag: u8, operand: u64, cell_max: u32) -> Option<CompressedOp> {
+    let no_operand = |op| if operand == 0 { Some(op) } else { None };
+    let cell_operand = || u32::try_from(operand).ok().filter(|&n| n <= cell_max);
+
+    match tag {
+        TAG_ADD => cell_operand().map(CompressedOp::Add),
+        TAG_SUB => cell_operand().map(CompressedOp::Sub),
+        TAG_BACK => operand.try_into().ok().map(CompressedOp::Back),
+        TAG_FORWARD => operand.try_into().ok().map(CompressedOp::Forward),
+        TAG_PUT_CHAR => no_operand(CompressedOp::PutChar),
+        TAG_GET_CHAR => no_operand(CompressedOp::GetChar),
+        TAG_LOOP_START => no_operand(CompressedOp::LoopStart),
+        TAG_LOOP_END => no_operand(CompressedOp::LoopEnd),
+        TAG_SET_ZERO => no_operand(CompressedOp::SetZero),
+        TAG_ASSERT => no_operand(CompressedOp::Assert),
+        TAG_IF_NONZERO => no_operand(CompressedOp::IfNonzero),
+        TAG_END_IF => no_operand(CompressedOp::EndIf),
+        TAG_SET_VALUE => cell_operand().map(CompressedOp::SetValue),
+        TAG_PUT_POINTER => no_operand(CompressedOp::PutPointer),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, compress_for, parse, run, Environment, Language, HELLO_BF};
+    use std::io::Cursor;
+
+
+    #[test]
+    fn test_round_trip() {
+        let code = compress(&parse(HELLO_BF, &Language::default()));
+
+        let bytes = encode_bytecode(&code);
+        assert_eq!(&bytes[0..6], b"BFKC\x01\x01");
+
+        let decoded = decode_bytecode(&bytes).unwrap();
+        assert_eq!(decoded.ops, code.ops);
+        assert_eq!(decoded.jump_table, code.jump_table);
+
+        let code = compress(&parse(">$", &Language::default().with_put_pointer('$').unwrap()));
+        assert_eq!(decode_bytecode(&encode_bytecode(&code)).unwrap().ops, code.ops);
+    }
+
+    #[test]
+    fn test_round_trip_wide_cells() {
+        let code = compress_for::<u16>(&parse(&"+".repeat(300), &Language::default()));
+
+        let bytes = encode_bytecode_for::<u16>(&code);
+        assert_eq!(bytes[5], 2);
+
+        assert_eq!(decode_bytecode_for::<u16>(&bytes).unwrap().ops, [CompressedOp::Add(300)]);
+        assert_eq!(decode_bytecode(&bytes).err(), Some(DecodeError::WrongCellWidth { expected: 1, actual: 2 }));
+        assert_eq!(decode_bytecode_for::<u32>(&bytes).err(), Some(DecodeError::WrongCellWidth { expected: 4, actual: 2 }));
+
+        // The width is of the cells compressed for, even if the operands would fit narrower ones
+        let code = compress_for::<u32>(&parse("+", &Language::default()));
+        let bytes = encode_bytecode_for::<u32>(&code);
+        assert_eq!(bytes[5], 4);
+        assert_eq!(decode_bytecode_for::<u32>(&bytes).unwrap().ops, code.ops);
+    }
+
+    #[test]
+    fn test_compile_to_bytecode() {
+        for opt_level in 0..3 {
+            let code = decode_bytecode(&compile_to_bytecode(HELLO_BF, &Language::default(), opt_level).unwrap()).unwrap();
+
+            let mut data = [0; 16];
+            let mut input = Cursor::new(vec![]);
+            let mut output = Vec::new();
+            run(&code, &mut Environment::new(&mut data, &mut input, &mut output)).unwrap();
+            assert_eq!(output, b"Hello World!\n");
+        }
+
+        assert_eq!(compile_to_bytecode("+]", &Language::default(), 2), Err(ParseError::UnmatchedLoopEnd { offset: 1 }));
+    }
+
+    #[test]
+    fn test_bytecode_file() {
+        let path = std::env::temp_dir().join(format!("bfk-test-{}.bfc", std::process::id()));
+        std::fs::write(&path, compile_to_bytecode(HELLO_BF, &Language::default(), 2).unwrap()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let run_output = |code: &Code<CompressedOp>| {
+            let mut data = [0; 16];
+            let mut input = Cursor::new(vec![]);
+            let mut output = Vec::new();
+            run(code, &mut Environment::new(&mut data, &mut input, &mut output)).unwrap();
+            output
+        };
+
+        assert!(is_bytecode(&bytes));
+        assert!(!is_bytecode(HELLO_BF.as_bytes()));
+        assert_eq!(run_output(&decode_bytecode(&bytes).unwrap()), run_output(&compress(&parse(HELLO_BF, &Language::default()))));
+    }
+
+    #[test]
+    fn test_reject_bad_header() {
+        let code = compress(&parse("+[-]", &Language::default()));
+        let bytes = encode_bytecode(&code);
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(decode_bytecode(&bad_magic).err(), Some(DecodeError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 2;
+        assert_eq!(decode_bytecode(&bad_version).err(), Some(DecodeError::UnsupportedVersion(2)));
+
+        let mut bad_cell_width = bytes.clone();
+        bad_cell_width[5] = 3;
+        assert_eq!(decode_bytecode(&bad_cell_width).err(), Some(DecodeError::UnsupportedCellWidth(3)));
+
+        assert_eq!(decode_bytecode(&bytes[..bytes.len() - 1]).err(),
+                   Some(DecodeError::BadLength { expected: bytes.len(), actual: bytes.len() - 1 }));
+    }
+
+    #[test]
+    fn test_reject_bad_code() {
+        let code = compress(&parse("+[-]", &Language::default()));
+        let mut bytes = encode_bytecode(&code);
+
+        // Point the loop start's jump out of bounds
+        let jump_start = HEADER_SIZE + code.ops.len() * OP_SIZE + JUMP_SIZE;
+        bytes[jump_start] = 100;
+        assert_eq!(decode_bytecode(&bytes).err(), Some(DecodeError::Invalid(ValidationError::BadJump { pc: 1 })));
+
+        // Add past the cell width
+        let mut bytes = encode_bytecode(&code);
+        bytes[HEADER_SIZE + 2] = 1;
+        assert_eq!(decode_bytecode(&bytes).err(), Some(DecodeError::BadOp { index: 0 }));
+    }
+}
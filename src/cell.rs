@@ -0,0 +1,52 @@
+//! Values stored in cells of the tape.
+
+use std::fmt;
+
+/// Unsigned integer stored in a cell of the tape
+pub trait Cell: Copy + Eq + Ord + fmt::Debug + fmt::Display + fmt::LowerHex {
+    /// Zero value
+    const ZERO: Self;
+    /// Maximum value
+    const MAX: Self;
+    /// Width in bits
+    const BITS: u32;
+
+    /// Convert from a count, wrapping around the cell's range
+    fn wrap_usize(n: usize) -> Self;
+    /// Convert from a `u32`, wrapping around the cell's range
+    fn wrap_u32(n: u32) -> Self;
+    /// Convert into a `u32`
+    fn to_u32(self) -> u32;
+
+    fn wrapping_add(self, n: Self) -> Self;
+    fn wrapping_sub(self, n: Self) -> Self;
+    fn saturating_add(self, n: Self) -> Self;
+    fn saturating_sub(self, n: Self) -> Self;
+    fn checked_add(self, n: Self) -> Option<Self>;
+    fn checked_sub(self, n: Self) -> Option<Self>;
+}
+
+macro_rules! impl_cell {
+    ($($t:ty),*) => {
+        $(
+            impl Cell for $t {
+                const ZERO: Self = 0;
+                const MAX: Self = <$t>::MAX;
+                const BITS: u32 = <$t>::BITS;
+
+                fn wrap_usize(n: usize) -> Self { n as $t }
+                fn wrap_u32(n: u32) -> Self { n as $t }
+                fn to_u32(self) -> u32 { self as u32 }
+
+                fn wrapping_add(self, n: Self) -> Self { <$t>::wrapping_add(self, n) }
+                fn wrapping_sub(self, n: Self) -> Self { <$t>::wrapping_sub(self, n) }
+                fn saturating_add(self, n: Self) -> Self { <$t>::saturating_add(self, n) }
+                fn saturating_sub(self, n: Self) -> Self { <$t>::saturating_sub(self, n) }
+                fn checked_add(self, n: Self) -> Option<Self> { <$t>::checked_add(self, n) }
+                fn checked_sub(self, n: Self) -> Option<Self> { <$t>::checked_sub(self, n) }
+            }
+        )*
+    }
+}
+
+impl_cell!(u8, u16, u32);
@@ -0,0 +1,188 @@
+//! Saving the state of a run to a file to resume it later.
+//!
+//! All integers are little-endian. The layout is:
+//!
+//! - magic `b"BFKS"`
+//! - version (`u8`)
+//! - fingerprint of the code being run (`u64`)
+//! - program counter (`u64`)
+//! - pointer (`u64`)
+//! - tape length (`u64`), then the tape
+//! - preloaded input length (`u64`), then the input not yet read
+//!
+//! Options and counters such as steps run aren't saved, so a resumed run
+//! starts over with default options.
+
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::{run, Code, Environment, RunOptions, Runnable, RuntimeError, StableEncoding};
+
+const MAGIC: &[u8; 4] = b"BFKS";
+const VERSION: u8 = 1;
+
+/// Errors occurring while saving or resuming a checkpoint.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CheckpointError {
+    /// Reading or writing the checkpoint file failed.
+    Io(std::io::ErrorKind),
+    /// File isn't a checkpoint this version can read.
+    Malformed,
+    /// Checkpoint was saved running other code.
+    CodeMismatch { expected: u64, actual: u64 },
+    /// Resumed run failed.
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for CheckpointError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckpointError::Io(kind) => write!(f, "I/O error ({})", kind),
+            CheckpointError::Malformed => write!(f, "not a checkpoint"),
+            CheckpointError::CodeMismatch { expected, actual } =>
+                write!(f, "checkpoint is of code {:016x} but the code is {:016x}", expected, actual),
+            CheckpointError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CheckpointError {}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(err: std::io::Error) -> Self {
+        CheckpointError::Io(err.kind())
+    }
+}
+
+impl<'a, R: Read, W: Write> Environment<'a, R, W> {
+    /// Save the tape, pointer, program counter and unread preloaded input to a
+    /// file, along with the fingerprint of the code being run
+    pub fn save_checkpoint<O: StableEncoding>(&self, path: impl AsRef<Path>, code: &Code<O>) -> Result<(), CheckpointError> {
+        let input = self.input_buffer.iter().copied().collect::<Vec<_>>();
+        let mut bytes = Vec::with_capacity(4 + 1 + 8 * 5 + self.data.len() + input.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&code.fingerprint().to_le_bytes());
+        bytes.extend_from_slice(&(self.pc as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.pointer as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.data.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(self.data);
+        bytes.extend_from_slice(&(input.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&input);
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+/// Bytes of a checkpoint, read from the front
+struct Fields<'b>(&'b [u8]);
+
+impl<'b> Fields<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], CheckpointError> {
+        if self.0.len() < len {
+            return Err(CheckpointError::Malformed);
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, CheckpointError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().expect("Took 8 bytes")))
+    }
+
+    fn usize(&mut self) -> Result<usize, CheckpointError> {
+        usize::try_from(self.u64()?).map_err(|_| CheckpointError::Malformed)
+    }
+}
+
+/// Restore a run of the code from the checkpoint file and run it to the end,
+/// returning the final tape. Fails without running if the checkpoint was saved
+/// running other code.
+pub fn resume<R, W, O>(path: impl AsRef<Path>, code: &Code<O>, reader: &mut R, writer: &mut W) -> Result<Vec<u8>, CheckpointError>
+    where R: Read, W: Write, O: Runnable + StableEncoding
+{
+    let bytes = fs::read(path)?;
+    let mut fields = Fields(&bytes);
+    if fields.take(4)? != MAGIC || fields.take(1)? != [VERSION] {
+        return Err(CheckpointError::Malformed);
+    }
+
+    let expected = fields.u64()?;
+    let actual = code.fingerprint();
+    if expected != actual {
+        return Err(CheckpointError::CodeMismatch { expected, actual });
+    }
+
+    let pc = fields.usize()?;
+    let pointer = fields.usize()?;
+    let tape_len = fields.usize()?;
+    let mut data = fields.take(tape_len)?.to_vec();
+    let input_len = fields.usize()?;
+    let input = fields.take(input_len)?;
+    if !fields.0.is_empty() || pointer >= data.len() || pc > code.ops.len() {
+        return Err(CheckpointError::Malformed);
+    }
+
+    let mut env = Environment::with_options(&mut data, reader, writer, RunOptions::default());
+    env.set_pointer(pointer).map_err(CheckpointError::Runtime)?;
+    env.set_pc(pc);
+    env.preload_input(input);
+    run(code, &mut env).map_err(CheckpointError::Runtime)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::ops::ControlFlow;
+    use crate::{compress, parse, run_with_hook, Language, HELLO_BF};
+
+
+    #[test]
+    fn test_resume() {
+        let language = Language::default();
+        let code = compress(&parse(&format!("{},[.,]", HELLO_BF), &language));
+        let path = std::env::temp_dir().join(format!("bfk-checkpoint-{}", std::process::id()));
+
+        let mut whole = Vec::new();
+        let mut data = [0; 16];
+        let mut input = Cursor::new(b"abc".to_vec());
+        let mut env = Environment::new(&mut data, &mut input, &mut whole);
+        run(&code, &mut env).unwrap();
+
+        // Stop after some output, with some input preloaded and some left in the reader
+        let mut first = Vec::new();
+        let mut data = [0; 16];
+        let mut input = Cursor::new(b"c".to_vec());
+        let mut env = Environment::new(&mut data, &mut input, &mut first);
+        env.preload_input(b"ab");
+        run_with_hook(&code, &mut env, |_, env| {
+            if env.writer.len() == 5 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        }).unwrap();
+        env.save_checkpoint(&path, &code).unwrap();
+        assert_eq!(first, b"Hello");
+
+        let mut rest = Vec::new();
+        let tape = resume(&path, &code, &mut input, &mut rest).unwrap();
+        first.extend(rest);
+        assert_eq!(from_utf8(&first), from_utf8(&whole));
+        assert_eq!(tape.len(), 16);
+
+        let other = compress(&parse("+.", &language));
+        assert_eq!(resume(&path, &other, &mut input, &mut Vec::new()),
+                   Err(CheckpointError::CodeMismatch { expected: code.fingerprint(), actual: other.fingerprint() }));
+
+        fs::write(&path, b"BFKS").unwrap();
+        assert_eq!(resume(&path, &code, &mut input, &mut Vec::new()), Err(CheckpointError::Malformed));
+        fs::remove_file(&path).unwrap();
+    }
+
+    fn from_utf8(bytes: &[u8]) -> &str {
+        std::str::from_utf8(bytes).unwrap()
+    }
+}
@@ -0,0 +1,4 @@
+//! Code generation for other languages from compressed code.
+
+pub mod llvm;
+pub mod rust;
@@ -0,0 +1,238 @@
+//! Textual LLVM IR.
+//!
+//! The generated module defines `void @run(i8* %tape)` running the code over
+//! `u8` cells starting at `%tape`, reading and writing with `getchar` and
+//! `putchar`. The pointer lives in an `alloca` for `mem2reg` to promote, so
+//! loops branch on the cell without `phi` nodes of their own. EOF stores zero
+//! and failed assertions call `abort`. Cells wrap and the pointer isn't checked.
+//!
+//! Tests compare the text only. It is meant to be checked with `llvm-as`, which
+//! reads these typed pointers up to LLVM 14.
+
+use crate::{Code, CompressedOp};
+
+/// Numbers SSA values and writes instructions
+struct Emitter {
+    ir: String,
+    next_value: usize,
+}
+
+impl Emitter {
+    fn line(&mut self, line: &str) {
+        self.ir.push_str(line);
+        self.ir.push('\n');
+    }
+
+    fn value(&mut self) -> String {
+        self.next_value += 1;
+        format!("%v{}", self.next_value)
+    }
+
+    /// Emit getting the address of the cell under the pointer
+    fn cell(&mut self) -> String {
+        let pointer = self.value();
+        let cell = self.value();
+        self.line(&format!("  {} = load i64, i64* %ptr", pointer));
+        self.line(&format!("  {} = getelementptr i8, i8* %tape, i64 {}", cell, pointer));
+        cell
+    }
+
+    /// Emit loading data under the pointer, returning its address and the data
+    fn load(&mut self) -> (String, String) {
+        let cell = self.cell();
+        let data = self.value();
+        self.line(&format!("  {} = load i8, i8* {}", data, cell));
+        (cell, data)
+    }
+
+    fn update_data(&mut self, instruction: &str, n: u32) {
+        let (cell, data) = self.load();
+        let result = self.value();
+        self.line(&format!("  {} = {} i8 {}, {}", result, instruction, data, n as u8));
+        self.line(&format!("  store i8 {}, i8* {}", result, cell));
+    }
+
+    fn move_pointer(&mut self, instruction: &str, n: usize) {
+        let pointer = self.value();
+        let result = self.value();
+        self.line(&format!("  {} = load i64, i64* %ptr", pointer));
+        self.line(&format!("  {} = {} i64 {}, {}", result, instruction, pointer, n));
+        self.line(&format!("  store i64 {}, i64* %ptr", result));
+    }
+
+    /// Emit branching to `zero` if data under the pointer is zero, else to `nonzero`
+    fn branch_on_data(&mut self, zero: &str, nonzero: &str) {
+        let (_, data) = self.load();
+        let is_zero = self.value();
+        self.line(&format!("  {} = icmp eq i8 {}, 0", is_zero, data));
+        self.line(&format!("  br i1 {}, label %{}, label %{}", is_zero, zero, nonzero));
+    }
+
+    fn label(&mut self, label: &str) {
+        self.line(&format!("{}:", label));
+    }
+}
+
+/// LLVM IR module running the code. Loop brackets must be matched, as validated code's are.
+pub fn emit(code: &Code<CompressedOp>) -> String {
+    let mut emitter = Emitter { ir: String::new(), next_value: 0 };
+    let has_assert = code.ops.contains(&CompressedOp::Assert);
+    let has_put_pointer = code.ops.contains(&CompressedOp::PutPointer);
+
+    emitter.line("declare i32 @getchar()");
+    emitter.line("declare i32 @putchar(i32)");
+    if has_assert {
+        emitter.line("declare void @abort()");
+    }
+    if has_put_pointer {
+        emitter.line("declare i32 @printf(i8*, ...)");
+        emitter.line("@pointer_format = private constant [6 x i8] c\"@%ld \\00\"");
+    }
+    emitter.line("");
+    emitter.line("define void @run(i8* %tape) {");
+    emitter.label("entry");
+    emitter.line("  %ptr = alloca i64");
+    emitter.line("  store i64 0, i64* %ptr");
+
+    for (pc, op) in code.ops.iter().enumerate() {
+        match *op {
+            CompressedOp::Add(n) => emitter.update_data("add", n),
+            CompressedOp::Sub(n) => emitter.update_data("sub", n),
+            CompressedOp::Forward(n) => emitter.move_pointer("add", n),
+            CompressedOp::Back(n) => emitter.move_pointer("sub", n),
+            CompressedOp::PutChar => {
+                let (_, data) = emitter.load();
+                let wide = emitter.value();
+                let result = emitter.value();
+                emitter.line(&format!("  {} = zext i8 {} to i32", wide, data));
+                emitter.line(&format!("  {} = call i32 @putchar(i32 {})", result, wide));
+            }
+            CompressedOp::GetChar => {
+                let read = emitter.value();
+                let is_eof = emitter.value();
+                let wide = emitter.value();
+                let data = emitter.value();
+                emitter.line(&format!("  {} = call i32 @getchar()", read));
+                emitter.line(&format!("  {} = icmp slt i32 {}, 0", is_eof, read));
+                emitter.line(&format!("  {} = select i1 {}, i32 0, i32 {}", wide, is_eof, read));
+                emitter.line(&format!("  {} = trunc i32 {} to i8", data, wide));
+                let cell = emitter.cell();
+                emitter.line(&format!("  store i8 {}, i8* {}", data, cell));
+            }
+            CompressedOp::SetZero => {
+                let cell = emitter.cell();
+                emitter.line(&format!("  store i8 0, i8* {}", cell));
+            }
+            CompressedOp::SetValue(n) => {
+                let cell = emitter.cell();
+                emitter.line(&format!("  store i8 {}, i8* {}", n as u8, cell));
+            }
+            CompressedOp::Assert => {
+                emitter.branch_on_data(&format!("fail{}", pc), &format!("pass{}", pc));
+                emitter.label(&format!("fail{}", pc));
+                emitter.line("  call void @abort()");
+                emitter.line("  unreachable");
+                emitter.label(&format!("pass{}", pc));
+            }
+            CompressedOp::PutPointer => {
+                let pointer = emitter.value();
+                let result = emitter.value();
+                emitter.line(&format!("  {} = load i64, i64* %ptr", pointer));
+                emitter.line(&format!(
+                    "  {} = call i32 (i8*, ...) @printf(i8* getelementptr ([6 x i8], [6 x i8]* @pointer_format, i64 0, i64 0), i64 {})",
+                    result, pointer,
+                ));
+            }
+            CompressedOp::LoopStart | CompressedOp::IfNonzero => {
+                emitter.line(&format!("  br label %loop{}", pc));
+                emitter.label(&format!("loop{}", pc));
+                emitter.branch_on_data(&format!("end{}", pc), &format!("body{}", pc));
+                emitter.label(&format!("body{}", pc));
+            }
+            CompressedOp::LoopEnd | CompressedOp::EndIf => {
+                let start = code.jump_table[pc] - 1;
+                let target = if *op == CompressedOp::LoopEnd { "loop" } else { "end" };
+                emitter.line(&format!("  br label %{}{}", target, start));
+                emitter.label(&format!("end{}", start));
+            }
+        }
+    }
+
+    emitter.line("  ret void");
+    emitter.line("}");
+    emitter.ir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, convert_if_loops, fold_clear_loops, parse, Language};
+
+    #[test]
+    fn test_emit() {
+        let code = compress(&parse(",[-].", &Language::default()));
+        assert_eq!(emit(&code), concat!(
+            "declare i32 @getchar()\n",
+            "declare i32 @putchar(i32)\n",
+            "\n",
+            "define void @run(i8* %tape) {\n",
+            "entry:\n",
+            "  %ptr = alloca i64\n",
+            "  store i64 0, i64* %ptr\n",
+            "  %v1 = call i32 @getchar()\n",
+            "  %v2 = icmp slt i32 %v1, 0\n",
+            "  %v3 = select i1 %v2, i32 0, i32 %v1\n",
+            "  %v4 = trunc i32 %v3 to i8\n",
+            "  %v5 = load i64, i64* %ptr\n",
+            "  %v6 = getelementptr i8, i8* %tape, i64 %v5\n",
+            "  store i8 %v4, i8* %v6\n",
+            "  br label %loop1\n",
+            "loop1:\n",
+            "  %v7 = load i64, i64* %ptr\n",
+            "  %v8 = getelementptr i8, i8* %tape, i64 %v7\n",
+            "  %v9 = load i8, i8* %v8\n",
+            "  %v10 = icmp eq i8 %v9, 0\n",
+            "  br i1 %v10, label %end1, label %body1\n",
+            "body1:\n",
+            "  %v11 = load i64, i64* %ptr\n",
+            "  %v12 = getelementptr i8, i8* %tape, i64 %v11\n",
+            "  %v13 = load i8, i8* %v12\n",
+            "  %v14 = sub i8 %v13, 1\n",
+            "  store i8 %v14, i8* %v12\n",
+            "  br label %loop1\n",
+            "end1:\n",
+            "  %v15 = load i64, i64* %ptr\n",
+            "  %v16 = getelementptr i8, i8* %tape, i64 %v15\n",
+            "  %v17 = load i8, i8* %v16\n",
+            "  %v18 = zext i8 %v17 to i32\n",
+            "  %v19 = call i32 @putchar(i32 %v18)\n",
+            "  ret void\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn test_emit_optimized() {
+        let language = Language::default().with_assert('?').unwrap();
+        let ir = emit(&convert_if_loops(&fold_clear_loops(&compress(&parse("?[>+<[-]]", &language)))));
+
+        assert!(ir.contains("declare void @abort()\n"));
+        assert!(ir.contains("  br i1 %v4, label %fail0, label %pass0\n"));
+        assert!(ir.contains("  store i8 0, i8* %v"));
+        // The if body falls through to its end instead of jumping back
+        assert!(ir.contains("  br label %end1\nend1:\n"));
+        assert!(!ir.contains("br label %loop1\nend1"));
+        assert!(!emit(&compress(&parse("+.", &language))).contains("@abort"));
+    }
+
+    #[test]
+    fn test_emit_put_pointer() {
+        let language = Language::default().with_put_pointer('$').unwrap();
+        let ir = emit(&compress(&parse(">>$", &language)));
+        assert!(ir.contains("declare i32 @printf(i8*, ...)\n@pointer_format = private constant [6 x i8] c\"@%ld \\00\"\n"));
+        assert!(ir.contains("  %v3 = load i64, i64* %ptr\n  %v4 = call i32 (i8*, ...) @printf("));
+
+        // Only declared when needed
+        assert!(!emit(&compress(&parse(">>.", &language))).contains("printf"));
+    }
+}
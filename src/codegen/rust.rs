@@ -0,0 +1,109 @@
+//! Standalone Rust programs.
+//!
+//! The generated `main.rs` needs nothing but `std`, so `rustc` builds it
+//! directly. It runs the code over a tape of `u8` cells of a fixed size, reading
+//! stdin and writing stdout through a buffer flushed before each read and at
+//! the end. EOF stores zero and failed assertions abort. Cells wrap, and moving
+//! the pointer off either end of the tape panics.
+
+use crate::{Code, CompressedOp};
+
+/// Source of a complete Rust program running the code over a tape of
+/// `tape_size` cells. Loop brackets must be matched, as validated code's are.
+pub fn emit_main(code: &Code<CompressedOp>, tape_size: usize) -> String {
+    let reads = code.ops.contains(&CompressedOp::GetChar);
+    let mut source = String::new();
+
+    source.push_str("#![allow(unused_mut)]\n\n");
+    source.push_str(if reads { "use std::io::{Read, Write};\n\n" } else { "use std::io::Write;\n\n" });
+    source.push_str("fn main() {\n");
+    source.push_str(&format!("    let mut tape = vec![0u8; {}];\n", tape_size));
+    source.push_str("    let mut ptr = 0usize;\n");
+    if reads {
+        source.push_str("    let stdin = std::io::stdin();\n");
+        source.push_str("    let mut input = stdin.lock();\n");
+        source.push_str("    let mut byte = [0u8; 1];\n");
+    }
+    source.push_str("    let stdout = std::io::stdout();\n");
+    source.push_str("    let mut output = std::io::BufWriter::new(stdout.lock());\n");
+
+    let mut depth = 1;
+    for op in &code.ops {
+        if matches!(op, CompressedOp::LoopEnd | CompressedOp::EndIf) {
+            depth -= 1;
+        }
+        let statement = match *op {
+            CompressedOp::Add(n) => format!("tape[ptr] = tape[ptr].wrapping_add({});", n as u8),
+            CompressedOp::Sub(n) => format!("tape[ptr] = tape[ptr].wrapping_sub({});", n as u8),
+            CompressedOp::Forward(n) => format!("ptr += {};", n),
+            CompressedOp::Back(n) => format!("ptr -= {};", n),
+            CompressedOp::PutChar => "output.write_all(&[tape[ptr]]).unwrap();".to_owned(),
+            CompressedOp::GetChar => "output.flush().unwrap();\n".to_owned()
+                + &"    ".repeat(depth)
+                + "tape[ptr] = if input.read_exact(&mut byte).is_ok() { byte[0] } else { 0 };",
+            CompressedOp::SetZero => "tape[ptr] = 0;".to_owned(),
+            CompressedOp::SetValue(n) => format!("tape[ptr] = {};", n as u8),
+            CompressedOp::Assert => "if tape[ptr] == 0 { std::process::abort(); }".to_owned(),
+            CompressedOp::PutPointer => "write!(output, \"@{} \", ptr).unwrap();".to_owned(),
+            CompressedOp::LoopStart => "while tape[ptr] != 0 {".to_owned(),
+            CompressedOp::IfNonzero => "if tape[ptr] != 0 {".to_owned(),
+            CompressedOp::LoopEnd | CompressedOp::EndIf => "}".to_owned(),
+        };
+        source.push_str(&"    ".repeat(depth));
+        source.push_str(&statement);
+        source.push('\n');
+        if matches!(op, CompressedOp::LoopStart | CompressedOp::IfNonzero) {
+            depth += 1;
+        }
+    }
+
+    source.push_str("    output.flush().unwrap();\n");
+    source.push_str("}\n");
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, convert_if_loops, fold_clear_loops, parse, Language};
+
+    #[test]
+    fn test_emit_main() {
+        let code = compress(&parse(",[-]>++.", &Language::default()));
+        assert_eq!(emit_main(&code, 16), concat!(
+            "#![allow(unused_mut)]\n",
+            "\n",
+            "use std::io::{Read, Write};\n",
+            "\n",
+            "fn main() {\n",
+            "    let mut tape = vec![0u8; 16];\n",
+            "    let mut ptr = 0usize;\n",
+            "    let stdin = std::io::stdin();\n",
+            "    let mut input = stdin.lock();\n",
+            "    let mut byte = [0u8; 1];\n",
+            "    let stdout = std::io::stdout();\n",
+            "    let mut output = std::io::BufWriter::new(stdout.lock());\n",
+            "    output.flush().unwrap();\n",
+            "    tape[ptr] = if input.read_exact(&mut byte).is_ok() { byte[0] } else { 0 };\n",
+            "    while tape[ptr] != 0 {\n",
+            "        tape[ptr] = tape[ptr].wrapping_sub(1);\n",
+            "    }\n",
+            "    ptr += 1;\n",
+            "    tape[ptr] = tape[ptr].wrapping_add(2);\n",
+            "    output.write_all(&[tape[ptr]]).unwrap();\n",
+            "    output.flush().unwrap();\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn test_emit_main_optimized() {
+        let language = Language::default().with_assert('?').unwrap();
+        let source = emit_main(&convert_if_loops(&fold_clear_loops(&compress(&parse("?[>+<[-]]", &language)))), 8);
+
+        assert!(source.starts_with("#![allow(unused_mut)]\n\nuse std::io::Write;\n"));
+        assert!(!source.contains("stdin"));
+        assert!(source.contains("    if tape[ptr] == 0 { std::process::abort(); }\n    if tape[ptr] != 0 {\n        ptr += 1;\n"));
+        assert!(source.contains("        tape[ptr] = 0;\n    }\n"));
+    }
+}
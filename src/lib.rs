@@ -1,7 +1,48 @@
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 use std::cmp::min;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::{ControlFlow, Range};
+
+mod analysis;
+mod asm;
+mod bench;
+mod bytecode;
+mod cell;
+mod checkpoint;
+pub mod codegen;
+#[cfg(feature = "memmap")]
+mod mmap;
+mod optimize;
+mod profile;
+mod random;
+mod report;
+mod script;
+mod stream;
+mod tape;
+#[cfg(feature = "timing")]
+mod timing;
+
+pub use analysis::{suggested_tape_size, BasicBlock, LoopSpan, Warning};
+pub use asm::{assemble, disassemble, AsmError};
+pub use bench::{bench_program, BenchResult};
+pub use bytecode::{compile_to_bytecode, decode_bytecode, decode_bytecode_for, encode_bytecode, encode_bytecode_for, is_bytecode, DecodeError};
+pub use cell::Cell;
+pub use checkpoint::{resume, CheckpointError};
+#[cfg(feature = "memmap")]
+pub use mmap::MmapTape;
+pub use optimize::{cancel_opposites, convert_if_loops, eliminate_dead_stores, fold_clear_loops, fold_multiply_loops, fold_plain_clear_loops, fold_set_values, optimize, optimize_hot, optimize_with_events, OptimizationEvent, Pass};
+pub use profile::{run_profiled, LoopProfile, Profile};
+pub use random::RandomInput;
+pub use report::{run_report, RunLimits, RunOutcome, RunReport};
+pub use script::{run_scripted, Interaction, ScriptError};
+pub use stream::OutputStream;
+pub use tape::{BiTape, DequeTape, Tape};
+#[cfg(feature = "timing")]
+pub use timing::{run_timed, OpTiming, TimingProfile};
 
 /// Language to parse and execute.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Language {
     inc: char,
     dec: char,
@@ -11,9 +52,20 @@ pub struct Language {
     get_char: char,
     loop_start: char,
     loop_end: char,
+    comment_open: Option<char>,
+    comment_close: Option<char>,
+    assert: Option<char>,
+    nop: Option<char>,
+    put_pointer: Option<char>,
+    expectations: bool,
 }
 
 impl Language {
+    /// Whether this is the default brainfuck language
+    pub fn is_default(&self) -> bool {
+        *self == Language::default()
+    }
+
     pub fn is_token(&self, ch: char) -> bool {
         self.inc == ch ||
             self.dec == ch ||
@@ -22,11 +74,95 @@ impl Language {
             self.put_char == ch ||
             self.get_char == ch ||
             self.loop_start == ch ||
-            self.loop_end == ch
+            self.loop_end == ch ||
+            self.assert == Some(ch) ||
+            self.nop == Some(ch) ||
+            self.put_pointer == Some(ch)
+    }
+
+    /// Use the character as an instruction asserting data is nonzero.
+    /// Returns None if it's already a token or a comment delimiter.
+    pub fn with_assert(mut self, ch: char) -> Option<Language> {
+        if self.is_token(ch) || self.comment_open == Some(ch) || self.comment_close == Some(ch) {
+            return None;
+        }
+        self.assert = Some(ch);
+        Some(self)
+    }
+
+    /// Use the character as an instruction doing nothing, which still takes a pc.
+    /// Returns None if it's already a token or a comment delimiter.
+    pub fn with_nop(mut self, ch: char) -> Option<Language> {
+        if self.is_token(ch) || self.comment_open == Some(ch) || self.comment_close == Some(ch) {
+            return None;
+        }
+        self.nop = Some(ch);
+        Some(self)
+    }
+
+    /// Use the character as an instruction writing the position of the pointer, such as `@5 `.
+    /// Returns None if it's already a token or a comment delimiter.
+    pub fn with_put_pointer(mut self, ch: char) -> Option<Language> {
+        if self.is_token(ch) || self.comment_open == Some(ch) || self.comment_close == Some(ch) {
+            return None;
+        }
+        self.put_pointer = Some(ch);
+        Some(self)
     }
 
-    /// Make from string. The length of string must be 8
-    pub fn make_from_string(s: &String) -> Option<Language> {
+    /// Skip lines starting with `-- expect:`, which carry output the program is
+    /// expected to write (see `extract_expectations`), even though `-` is a token
+    pub fn with_expectations(mut self) -> Language {
+        self.expectations = true;
+        self
+    }
+
+    /// Treat everything from `open` up to the next `close` as a comment, even tokens.
+    /// Returns None if either delimiter is a token.
+    pub fn with_comment_delimiters(mut self, open: char, close: char) -> Option<Language> {
+        if self.is_token(open) || self.is_token(close) {
+            return None;
+        }
+        self.comment_open = Some(open);
+        self.comment_close = Some(close);
+        Some(self)
+    }
+
+    /// Tokens in the source with their byte offsets, skipping comment blocks
+    /// and expectation lines if the language has them
+    fn token_indices<'s>(&'s self, source: &'s str) -> impl Iterator<Item = (usize, char)> + 's {
+        let mut state = TokenState::default();
+        source.char_indices().filter(move |&(index, ch)| state.accept(self, ch, &source[index..]))
+    }
+
+    /// Whether any decimal digit is a token, which makes run-length encoding ambiguous
+    fn has_digit_token(&self) -> bool {
+        ('0'..='9').any(|ch| self.is_token(ch))
+    }
+
+    /// Operation the token stands for
+    fn token_op(&self, ch: char) -> Option<Op> {
+        match ch {
+            ch if self.inc == ch => Some(Op::Inc),
+            ch if self.dec == ch => Some(Op::Dec),
+            ch if self.inc_ptr == ch => Some(Op::IncPtr),
+            ch if self.dec_ptr == ch => Some(Op::DecPtr),
+            ch if self.put_char == ch => Some(Op::PutChar),
+            ch if self.get_char == ch => Some(Op::GetChar),
+            ch if self.loop_start == ch => Some(Op::LoopStart),
+            ch if self.loop_end == ch => Some(Op::LoopEnd),
+            ch if self.assert == Some(ch) => Some(Op::Assert),
+            ch if self.nop == Some(ch) => Some(Op::Nop),
+            ch if self.put_pointer == Some(ch) => Some(Op::PutPointer),
+            _ => None,
+        }
+    }
+
+    /// Make from string. The length of string must be 8, with characters in the
+    /// order of `+-><,.[]`: inc, dec, inc_ptr, dec_ptr, get_char, put_char,
+    /// loop_start and loop_end. `to_string` gives the same string back.
+    /// Any character may be a token, including whitespace.
+    pub fn make_from_string(s: &str) -> Option<Language> {
         if s.chars().count() != 8 {
             return None;
         }
@@ -43,11 +179,37 @@ impl Language {
                 put_char: chars[5],
                 loop_start: chars[6],
                 loop_end: chars[7],
+                comment_open: None,
+                comment_close: None,
+                assert: None,
+                nop: None,
+                put_pointer: None,
+                expectations: false,
             }
         )
     }
 }
 
+/// Writes the 8 characters `make_from_string` reads, so the default language is `+-><,.[]`.
+/// Comment delimiters and the optional instructions aren't written.
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for ch in [
+            self.inc,
+            self.dec,
+            self.inc_ptr,
+            self.dec_ptr,
+            self.get_char,
+            self.put_char,
+            self.loop_start,
+            self.loop_end,
+        ] {
+            write!(f, "{}", ch)?;
+        }
+        Ok(())
+    }
+}
+
 /// Provides default brainfuck language
 impl Default for Language {
     /// Default brainfuck language
@@ -60,13 +222,19 @@ impl Default for Language {
             get_char: ',',
             put_char: '.',
             loop_start: '[',
-            loop_end: ']'
+            loop_end: ']',
+            comment_open: None,
+            comment_close: None,
+            assert: None,
+            nop: None,
+            put_pointer: None,
+            expectations: false,
         }
     }
 }
 
 /// Operations
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum Op {
     /// Increment data.
     Inc,
@@ -84,15 +252,23 @@ pub enum Op {
     LoopStart,
     /// End of loop.
     LoopEnd,
+    /// Fail if data is zero.
+    Assert,
+    /// Set data to zero, as a clearing loop `[-]` does.
+    SetZero,
+    /// Do nothing. Compressing drops it.
+    Nop,
+    /// Write the position of the pointer.
+    PutPointer,
 }
 
 /// Compressed operations
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum CompressedOp {
-    /// Add to data
-    Add(u8),
-    /// Subtract from data
-    Sub(u8),
+    /// Add to data, wrapped around the range of cells the code is compressed for
+    Add(u32),
+    /// Subtract from data, wrapped around the range of cells the code is compressed for
+    Sub(u32),
     /// Move back pointer
     Back(usize),
     /// Move forward pointer
@@ -105,417 +281,3553 @@ pub enum CompressedOp {
     LoopStart,
     /// End of loop.
     LoopEnd,
+    /// Set data to zero, as a clearing loop `[-]` does.
+    SetZero,
+    /// Set data to the value, wrapped around the range of cells, as `[-]` followed by adding it does.
+    SetValue(u32),
+    /// Fail if data is zero.
+    Assert,
+    /// Start of a loop body which runs at most once, as it clears the data it starts on.
+    IfNonzero,
+    /// End of the body started by `IfNonzero`, which never jumps back.
+    EndIf,
+    /// Write the position of the pointer.
+    PutPointer,
 }
 
-/// Execution environment.
-pub struct Environment<'a, R, W> {
-    data: &'a mut [u8],
-    pc: usize,
-    pointer: usize,
-    reader: &'a mut R,
-    writer: &'a mut W,
+/// Operations with a byte encoding fixed by hand, unlike their derived `Hash`
+pub trait StableEncoding {
+    /// Append the encoding of the operation: a tag (`u64`) followed by its operand,
+    /// if any, as a little-endian `u32` for data or `u64` for pointer moves
+    fn encode(&self, bytes: &mut Vec<u8>);
 }
 
-impl<'a, R: Read, W: Write> Environment<'a, R, W> {
-    /// Add to data
-    pub fn add(&mut self, n: u8) {
-        self.data[self.pointer] = self.data[self.pointer].wrapping_add(n);
+impl StableEncoding for Op {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let tag: u64 = match self {
+            Op::Inc => 0,
+            Op::Dec => 1,
+            Op::IncPtr => 2,
+            Op::DecPtr => 3,
+            Op::PutChar => 4,
+            Op::GetChar => 5,
+            Op::LoopStart => 6,
+            Op::LoopEnd => 7,
+            Op::Assert => 8,
+            Op::SetZero => 9,
+            Op::Nop => 10,
+            Op::PutPointer => 11,
+        };
+        bytes.extend_from_slice(&tag.to_le_bytes());
     }
+}
 
-    /// Sub from data
-    pub fn sub(&mut self, n: u8) {
-        self.data[self.pointer] = self.data[self.pointer].wrapping_sub(n);
+impl StableEncoding for CompressedOp {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let (tag, operand): (u64, Vec<u8>) = match *self {
+            CompressedOp::Add(n) => (0, n.to_le_bytes().to_vec()),
+            CompressedOp::Sub(n) => (1, n.to_le_bytes().to_vec()),
+            CompressedOp::Back(n) => (2, (n as u64).to_le_bytes().to_vec()),
+            CompressedOp::Forward(n) => (3, (n as u64).to_le_bytes().to_vec()),
+            CompressedOp::PutChar => (4, Vec::new()),
+            CompressedOp::GetChar => (5, Vec::new()),
+            CompressedOp::LoopStart => (6, Vec::new()),
+            CompressedOp::LoopEnd => (7, Vec::new()),
+            CompressedOp::SetZero => (8, Vec::new()),
+            CompressedOp::SetValue(n) => (9, n.to_le_bytes().to_vec()),
+            CompressedOp::Assert => (10, Vec::new()),
+            CompressedOp::IfNonzero => (11, Vec::new()),
+            CompressedOp::EndIf => (12, Vec::new()),
+            CompressedOp::PutPointer => (13, Vec::new()),
+        };
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&operand);
     }
+}
 
-    /// Add to pointer
-    pub fn add_ptr(&mut self, n: usize) {
-        let pointer_max = self.data.len() - 1;
+/// Operations which may start or end a loop
+pub trait Bracket {
+    /// Whether the operation starts a loop
+    fn is_loop_start(&self) -> bool;
+    /// Whether the operation ends a loop
+    fn is_loop_end(&self) -> bool;
+}
 
-        // Avoiding overflow panic
-        self.pointer = if pointer_max >= n && pointer_max - n <= self.pointer {
-            pointer_max
-        } else {
-            min(pointer_max, self.pointer + n)
-        }
+impl Bracket for Op {
+    fn is_loop_start(&self) -> bool {
+        *self == Op::LoopStart
     }
 
-    /// Sub from pointer
-    pub fn sub_ptr(&mut self, n: usize) {
-        // Avoiding underflow panic
-        self.pointer = if self.pointer <= n { 0 } else { self.pointer - n };
+    fn is_loop_end(&self) -> bool {
+        *self == Op::LoopEnd
     }
+}
 
-    /// Print data under the pointer as a character
-    pub fn put_char(&mut self) {
-        write!(self.writer, "{}", self.data[self.pointer] as char).unwrap();
-        self.writer.flush().unwrap();
+impl Bracket for CompressedOp {
+    fn is_loop_start(&self) -> bool {
+        matches!(self, CompressedOp::LoopStart | CompressedOp::IfNonzero)
     }
 
-    /// Read a character into data
-    pub fn read_char(&mut self) {
-        let char = self.reader
-            .bytes()
-            .next()
-            .and_then(|result| result.ok());
-        self.data[self.pointer] = char.unwrap_or(0);
+    fn is_loop_end(&self) -> bool {
+        matches!(self, CompressedOp::LoopEnd | CompressedOp::EndIf)
     }
+}
 
-    /// Increment program pointer
-    pub fn advance_pc(&mut self) {
-        self.pc += 1;
-    }
+/// How reading a character waits for input.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InputAvailability {
+    /// Consume preloaded input first, then read from the reader,
+    /// blocking until it yields a byte or reaches EOF.
+    Blocking,
+    /// Consume preloaded input only. The reader is never touched, so
+    /// reading never blocks and is EOF once the preloaded input is exhausted.
+    Immediate,
+}
 
-    /// Set program counter
-    pub fn set_pc(&mut self, pc: usize) {
-        self.pc = pc;
+/// What to do when the pointer moves outside of the tape.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PointerPolicy {
+    /// Stop the pointer at the edge of the tape.
+    Clamp,
+    /// Fail with a runtime error.
+    Error,
+}
+
+/// What to store into data when reading a character at EOF.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EofMode {
+    /// Store zero.
+    Zero,
+    /// Leave data unchanged.
+    Unchanged,
+    /// Store the maximum value of a cell.
+    Max,
+}
+
+/// What to do when adding to or subtracting from data goes out of the cell's range.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CellOverflow {
+    /// Wrap around.
+    Wrap,
+    /// Stop at the minimum or maximum value of a cell. Compressed runs
+    /// longer than the cell's range are already wrapped, so they don't saturate.
+    Saturate,
+    /// Fail with a runtime error.
+    Error,
+}
+
+/// When to flush the writer.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlushPolicy {
+    /// Flush after every character put.
+    PerByte,
+    /// Flush once when the environment is finished.
+    OnFinish,
+}
+
+/// How to write data when putting a character.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// Write data as a character.
+    Raw,
+    /// Write data as a decimal number, padded and followed by a separator as
+    /// the options set.
+    Decimal,
+    /// Write data as at least two lowercase hex digits followed by a space.
+    Hex,
+}
+
+/// What character to write when putting raw data too wide for a byte.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WideCharPolicy {
+    /// Write the character of the low byte.
+    Truncate,
+    /// Fail with `RuntimeError::NonByteOutput`.
+    Error,
+    /// Write the character with the data as its code point, failing if it isn't one.
+    Utf8,
+}
+
+/// How many bytes putting or reading a character transfers.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IoWidth {
+    /// One byte, the low byte of data.
+    Byte,
+    /// Two bytes, the low 16 bits of data in little-endian. When EOF comes after
+    /// the low byte, the high byte is zero, unchanged or `0xff` as `EofMode` says.
+    Word,
+}
+
+/// Options to run operations with.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RunOptions {
+    tape_size: usize,
+    input_availability: InputAvailability,
+    pointer_policy: PointerPolicy,
+    eof_mode: EofMode,
+    cell_overflow: CellOverflow,
+    flush_policy: FlushPolicy,
+    step_limit: Option<u64>,
+    output_format: OutputFormat,
+    wide_char_policy: WideCharPolicy,
+    echo_input: bool,
+    io_width: IoWidth,
+    max_output: Option<u64>,
+    max_input: Option<u64>,
+    modulus: Option<u32>,
+    trap_uninitialized: bool,
+    forward_only: bool,
+    condition_cell: Option<usize>,
+    decimal_separator: String,
+    decimal_width: Option<usize>,
+    max_cells: Option<usize>,
+}
+
+impl RunOptions {
+    /// Start building options from the defaults
+    pub fn builder() -> RunOptionsBuilder {
+        RunOptionsBuilder { options: RunOptions::default() }
     }
 
-    /// Read data under the pointer
-    pub fn read_data(&self) -> u8 {
-        self.data[self.pointer]
+    /// Tape size in cells used by `execute`
+    pub fn tape_size(&self) -> usize {
+        self.tape_size
     }
+}
 
-    pub fn new(data: &'a mut [u8], reader: &'a mut R, writer: &'a mut W) -> Self {
-        Environment {
-            data,
-            writer,
-            reader,
-            pointer: 0,
-            pc: 0
+/// Provides default options, which behave as the plain interpreter
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            tape_size: 1024 * 1024,
+            input_availability: InputAvailability::Blocking,
+            pointer_policy: PointerPolicy::Clamp,
+            eof_mode: EofMode::Zero,
+            cell_overflow: CellOverflow::Wrap,
+            flush_policy: FlushPolicy::OnFinish,
+            step_limit: None,
+            output_format: OutputFormat::Raw,
+            wide_char_policy: WideCharPolicy::Truncate,
+            echo_input: false,
+            io_width: IoWidth::Byte,
+            max_output: None,
+            max_input: None,
+            modulus: None,
+            trap_uninitialized: false,
+            forward_only: false,
+            condition_cell: None,
+            decimal_separator: String::new(),
+            decimal_width: None,
+            max_cells: None,
         }
     }
 }
 
-/// Executable brainfuck operations
-pub struct Code<T> {
-    ops: Vec<T>,
-    jump_table: Vec<usize>
+/// Builder for `RunOptions`.
+#[derive(Debug, Clone)]
+pub struct RunOptionsBuilder {
+    options: RunOptions,
 }
 
-/// Parse source code into the operations
-pub fn parse(source: &String, language: &Language) -> Code<Op> {
-    let token_chars = source.chars().filter(|&c| language.is_token(c));
+impl RunOptionsBuilder {
+    /// Tape size in cells used by `execute`
+    pub fn tape_size(mut self, tape_size: usize) -> Self {
+        self.options.tape_size = tape_size;
+        self
+    }
 
-    let mut ops = Vec::new();
-    let mut jump_table = vec![0; token_chars.clone().count()];
-    let mut map_stack = Vec::new();
+    /// How reading a character waits for input
+    pub fn input_availability(mut self, input_availability: InputAvailability) -> Self {
+        self.options.input_availability = input_availability;
+        self
+    }
 
-    for (pc, char) in token_chars.enumerate() {
-        match char {
-            ch if language.inc == ch => ops.push(Op::Inc),
-            ch if language.dec == ch => ops.push(Op::Dec),
-            ch if language.inc_ptr == ch => ops.push(Op::IncPtr),
-            ch if language.dec_ptr == ch => ops.push(Op::DecPtr),
-            ch if language.put_char == ch => ops.push(Op::PutChar),
-            ch if language.get_char == ch => ops.push(Op::GetChar),
-            ch if language.loop_start == ch => {
-                ops.push(Op::LoopStart);
-                map_stack.push(pc);
-            }
-            ch if language.loop_end == ch => {
-                ops.push(Op::LoopEnd);
-                let begin = map_stack.pop().expect("Unmatched loop end");
-                jump_table[begin] = pc + 1;
-                jump_table[pc] = begin + 1;
-            }
-            _ => ()
-        }
+    /// What to do when the pointer moves outside of the tape
+    pub fn pointer_policy(mut self, pointer_policy: PointerPolicy) -> Self {
+        self.options.pointer_policy = pointer_policy;
+        self
     }
 
-    Code { ops, jump_table }
-}
+    /// What to store into data when reading a character at EOF
+    pub fn eof_mode(mut self, eof_mode: EofMode) -> Self {
+        self.options.eof_mode = eof_mode;
+        self
+    }
 
-/// Compress operations
-pub fn compress(code: &Code<Op>) -> Code<CompressedOp> {
-    let mut compressed_ops = Vec::new();
+    /// What to do when data goes out of the cell's range
+    pub fn cell_overflow(mut self, cell_overflow: CellOverflow) -> Self {
+        self.options.cell_overflow = cell_overflow;
+        self
+    }
 
-    let mut last_op: Option<Op> = None;
-    let mut count: usize = 1;
-    let mut pc = 0;
-    let mut map_stack = Vec::new();
-    let mut op_groups: Vec<(Op, usize)> = Vec::new();
+    /// When to flush the writer
+    pub fn flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.options.flush_policy = flush_policy;
+        self
+    }
 
-    fn is_repeatable(op: Op) -> bool {
-        op == Op::Inc || op == Op::Dec || op == Op::IncPtr || op == Op::DecPtr
+    /// Maximum number of operations to run, if any
+    pub fn step_limit(mut self, step_limit: Option<u64>) -> Self {
+        self.options.step_limit = step_limit;
+        self
     }
 
-    for op in code.ops.iter() {
-        if let Some(last_op_) = last_op {
-            if last_op_ == *op && is_repeatable(last_op_) {
-                count += 1;
-            } else {
-                op_groups.push((last_op_, count));
+    /// How to write data when putting a character
+    pub fn output_format(mut self, output_format: OutputFormat) -> Self {
+        self.options.output_format = output_format;
+        self
+    }
 
-                last_op = Some(op.clone());
-                count = 1;
-            }
-        } else {
-            last_op = Some(op.clone());
-        }
+    /// What to write after each number in decimal output
+    pub fn decimal_separator(mut self, decimal_separator: &str) -> Self {
+        self.options.decimal_separator = decimal_separator.to_owned();
+        self
     }
 
-    if last_op.is_some() {
-        op_groups.push((last_op.unwrap(), count));
+    /// Width to pad numbers in decimal output to with leading zeros, if any
+    pub fn decimal_width(mut self, decimal_width: Option<usize>) -> Self {
+        self.options.decimal_width = decimal_width;
+        self
     }
 
-    let mut jump_table = vec![0; op_groups.len()];
+    /// What character to write when putting raw data too wide for a byte
+    pub fn wide_char_policy(mut self, wide_char_policy: WideCharPolicy) -> Self {
+        self.options.wide_char_policy = wide_char_policy;
+        self
+    }
 
-    macro_rules! read_op {
-        ($stmt:stmt) => {
-            {
-               $stmt
-               pc += 1;
-            }
-        }
+    /// Whether to write characters read to the writer, like a terminal echoes them
+    pub fn echo_input(mut self, echo_input: bool) -> Self {
+        self.options.echo_input = echo_input;
+        self
     }
 
-    for (op, count) in op_groups {
-        match op {
-            Op::Inc => read_op!(compressed_ops.push(CompressedOp::Add(count as u8))),
-            Op::Dec => read_op!(compressed_ops.push(CompressedOp::Sub(count as u8))),
-            Op::IncPtr => read_op!(compressed_ops.push(CompressedOp::Forward(count))),
-            Op::DecPtr => read_op!(compressed_ops.push(CompressedOp::Back(count))),
-            Op::PutChar => read_op!(compressed_ops.push(CompressedOp::PutChar)),
-            Op::GetChar => read_op!(compressed_ops.push(CompressedOp::GetChar)),
-            Op::LoopStart => read_op!({
-                compressed_ops.push(CompressedOp::LoopStart);
-                map_stack.push(pc);
-            }),
-            Op::LoopEnd => read_op!({
-                compressed_ops.push(CompressedOp::LoopEnd);
-                let begin = map_stack.pop().expect("Unmatched loop end");
-                jump_table[begin] = pc + 1;
-                jump_table[pc] = begin + 1;
-            }),
-        }
+    /// How many bytes putting or reading a character transfers
+    pub fn io_width(mut self, io_width: IoWidth) -> Self {
+        self.options.io_width = io_width;
+        self
     }
 
-    Code { ops: compressed_ops, jump_table }
-}
+    /// Maximum number of bytes putting characters may write, if any
+    pub fn max_output(mut self, max_output: Option<u64>) -> Self {
+        self.options.max_output = max_output;
+        self
+    }
 
-/// Represents runnable operations
-pub trait Runnable {
-    /// Run the operation over code and environment
-    fn run<R: Read, W: Write>(&self, code: &Code<Self>, env: &mut Environment<R, W>) where Self: Sized;
+    /// Maximum number of bytes reading characters may consume, if any
+    pub fn max_input(mut self, max_input: Option<u64>) -> Self {
+        self.options.max_input = max_input;
+        self
+    }
 
-    fn process_loop_start<R: Read, W: Write>(code: &Code<Self>, env: &mut Environment<R, W>) where Self: Sized {
-        if env.read_data() == 0 {
-            env.set_pc(code.jump_table[env.pc]);
-        } else {
-            env.advance_pc();
-        };
+    /// Number data is taken modulo when adding and subtracting, instead of the
+    /// cell's range. It should be within the cell's range, and zero is the same
+    /// as none. Compressed runs are still wrapped around the cell's range, so
+    /// compress for cells wide enough that runs don't wrap.
+    pub fn modulus(mut self, modulus: Option<u32>) -> Self {
+        self.options.modulus = modulus;
+        self
     }
 
-    fn process_loop_end<R: Read, W: Write>(code: &Code<Self>, env: &mut Environment<R, W>) where Self: Sized {
-        if env.read_data() != 0 {
-            env.set_pc(code.jump_table[env.pc]);
-        } else {
-            env.advance_pc();
-        }
+    /// Whether testing data in loops, putting it or asserting on it fails for cells
+    /// the program never wrote. Adding and subtracting count as writes.
+    pub fn trap_uninitialized(mut self, trap_uninitialized: bool) -> Self {
+        self.options.trap_uninitialized = trap_uninitialized;
+        self
+    }
+
+    /// Whether loop ends fail instead of jumping back, so loop bodies run at most
+    /// once and every run terminates
+    pub fn forward_only(mut self, forward_only: bool) -> Self {
+        self.options.forward_only = forward_only;
+        self
+    }
+
+    /// Cell loops test instead of the cell under the pointer, if any. Compressing
+    /// folds loops like `[-]` assuming the cell under the pointer is tested, so
+    /// code run with a condition cell shouldn't be compressed.
+    pub fn condition_cell(mut self, condition_cell: Option<usize>) -> Self {
+        self.options.condition_cell = condition_cell;
+        self
+    }
+
+    /// Maximum number of distinct cells the pointer may move onto or over, counting
+    /// the cell each move starts from, if any. A move by several cells counts every
+    /// cell passed, so compressing doesn't change what fits in the limit.
+    pub fn max_cells(mut self, max_cells: Option<usize>) -> Self {
+        self.options.max_cells = max_cells;
+        self
+    }
+
+    pub fn build(self) -> RunOptions {
+        self.options
     }
 }
 
-impl Runnable for Op {
-    fn run<R: Read, W: Write>(&self, code: &Code<Self>, env: &mut Environment<R, W>) {
+/// Errors occurring while running operations.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RuntimeError {
+    /// Pointer moved before the start of the tape, to the negative index, which
+    /// saturates at `isize::MIN`.
+    PointerUnderflow { index: isize, pc: usize, source_offset: Option<usize> },
+    /// Pointer moved past the end of the tape of the length, to the index, which
+    /// saturates at `usize::MAX`.
+    PointerOverflow { index: usize, tape_len: usize, pc: usize, source_offset: Option<usize> },
+    /// Data went below the minimum value of a cell.
+    CellUnderflow { pc: usize, source_offset: Option<usize> },
+    /// Data went above the maximum value of a cell.
+    CellOverflow { pc: usize, source_offset: Option<usize> },
+    /// Ran more operations than the step limit.
+    StepLimitExceeded { pc: usize, source_offset: Option<usize> },
+    /// Write to the watched cell matched the watchpoint's predicate.
+    Watchpoint { index: usize, old: u32, new: u32, pc: usize, source_offset: Option<usize> },
+    /// Putting a character would write more bytes than the output limit.
+    OutputLimitExceeded { pc: usize, source_offset: Option<usize> },
+    /// Reading a character would consume more bytes than the input limit.
+    InputLimitExceeded { pc: usize, source_offset: Option<usize> },
+    /// Pointer moved onto more distinct cells than the limit.
+    MemoryLimitExceeded { limit: usize, pc: usize, source_offset: Option<usize> },
+    /// Data of the cell at the index was read before the program wrote it.
+    UninitializedRead { index: usize, pc: usize, source_offset: Option<usize> },
+    /// Raw data put as a character doesn't fit in a byte, or isn't a code point
+    /// under `WideCharPolicy::Utf8`.
+    NonByteOutput { value: u32, pc: usize, source_offset: Option<usize> },
+    /// Loop end would jump back while only jumping forward is allowed.
+    BackwardJumpDisallowed { pc: usize, source_offset: Option<usize> },
+    /// Data of the cell at the index was zero at an assertion.
+    AssertionFailed { cell: usize, pc: usize, source_offset: Option<usize> },
+    /// Reading from the reader or writing to the writer failed.
+    Io { kind: std::io::ErrorKind, pc: usize, source_offset: Option<usize> },
+}
+
+impl RuntimeError {
+    /// Program counter of the operation that caused the error
+    pub fn pc(&self) -> usize {
+        *self.location().0
+    }
+
+    /// Byte offset in the source of the operation that caused the error, if known
+    pub fn source_offset(&self) -> Option<usize> {
+        *self.location().1
+    }
+
+    fn location(&self) -> (&usize, &Option<usize>) {
         match self {
-            Op::Inc => { env.add(1); env.advance_pc(); }
-            Op::Dec => { env.sub(1); env.advance_pc(); }
-            Op::IncPtr => { env.add_ptr(1); env.advance_pc(); }
-            Op::DecPtr => { env.sub_ptr(1); env.advance_pc(); }
-            Op::PutChar => { env.put_char(); env.advance_pc(); }
-            Op::GetChar => { env.read_char(); env.advance_pc(); }
-            Op::LoopStart => {
-                Runnable::process_loop_start(code, env);
-            }
-            Op::LoopEnd => {
-                Runnable::process_loop_end(code, env);
-            }
+            RuntimeError::PointerUnderflow { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::PointerOverflow { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::CellUnderflow { pc, source_offset } => (pc, source_offset),
+            RuntimeError::CellOverflow { pc, source_offset } => (pc, source_offset),
+            RuntimeError::StepLimitExceeded { pc, source_offset } => (pc, source_offset),
+            RuntimeError::OutputLimitExceeded { pc, source_offset } => (pc, source_offset),
+            RuntimeError::InputLimitExceeded { pc, source_offset } => (pc, source_offset),
+            RuntimeError::MemoryLimitExceeded { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::Watchpoint { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::UninitializedRead { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::NonByteOutput { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::BackwardJumpDisallowed { pc, source_offset } => (pc, source_offset),
+            RuntimeError::AssertionFailed { pc, source_offset, .. } => (pc, source_offset),
+            RuntimeError::Io { pc, source_offset, .. } => (pc, source_offset),
         }
     }
-}
 
-impl Runnable for CompressedOp {
-    fn run<R: Read, W: Write>(&self, code: &Code<Self>, env: &mut Environment<R, W>) where Self: Sized {
+    fn with_source_offset(mut self, offset: Option<usize>) -> Self {
+        match &mut self {
+            RuntimeError::PointerUnderflow { source_offset, .. } => *source_offset = offset,
+            RuntimeError::PointerOverflow { source_offset, .. } => *source_offset = offset,
+            RuntimeError::CellUnderflow { source_offset, .. } => *source_offset = offset,
+            RuntimeError::CellOverflow { source_offset, .. } => *source_offset = offset,
+            RuntimeError::StepLimitExceeded { source_offset, .. } => *source_offset = offset,
+            RuntimeError::OutputLimitExceeded { source_offset, .. } => *source_offset = offset,
+            RuntimeError::InputLimitExceeded { source_offset, .. } => *source_offset = offset,
+            RuntimeError::MemoryLimitExceeded { source_offset, .. } => *source_offset = offset,
+            RuntimeError::Watchpoint { source_offset, .. } => *source_offset = offset,
+            RuntimeError::UninitializedRead { source_offset, .. } => *source_offset = offset,
+            RuntimeError::NonByteOutput { source_offset, .. } => *source_offset = offset,
+            RuntimeError::BackwardJumpDisallowed { source_offset, .. } => *source_offset = offset,
+            RuntimeError::AssertionFailed { source_offset, .. } => *source_offset = offset,
+            RuntimeError::Io { source_offset, .. } => *source_offset = offset,
+        }
+        self
+    }
+
+    /// Write what went wrong, without where
+    fn write_message(&self, f: &mut dyn fmt::Write) -> fmt::Result {
         match self {
-            CompressedOp::Add(n) => { env.add(*n); env.advance_pc(); }
-            CompressedOp::Sub(n) => { env.sub(*n); env.advance_pc(); }
-            CompressedOp::Back(n) => { env.sub_ptr(*n); env.advance_pc(); }
-            CompressedOp::Forward(n) => { env.add_ptr(*n); env.advance_pc(); }
-            CompressedOp::PutChar => { env.put_char(); env.advance_pc(); }
-            CompressedOp::GetChar => { env.read_char(); env.advance_pc(); }
-            CompressedOp::LoopStart => {
-                Runnable::process_loop_start(code, env);
-            }
-            CompressedOp::LoopEnd => {
-                Runnable::process_loop_end(code, env);
-            }
+            RuntimeError::PointerUnderflow { index, .. } => write!(f, "pointer underflow to {}", index),
+            RuntimeError::PointerOverflow { index, tape_len, .. } =>
+                write!(f, "pointer overflow to {} past tape length {}", index, tape_len),
+            RuntimeError::CellUnderflow { .. } => write!(f, "cell underflow"),
+            RuntimeError::CellOverflow { .. } => write!(f, "cell overflow"),
+            RuntimeError::StepLimitExceeded { .. } => write!(f, "step limit exceeded"),
+            RuntimeError::OutputLimitExceeded { .. } => write!(f, "output limit exceeded"),
+            RuntimeError::InputLimitExceeded { .. } => write!(f, "input limit exceeded"),
+            RuntimeError::MemoryLimitExceeded { limit, .. } => write!(f, "memory limit of {} cells exceeded", limit),
+            RuntimeError::Watchpoint { index, old, new, .. } =>
+                write!(f, "watchpoint on cell {} hit writing {} over {}", index, new, old),
+            RuntimeError::UninitializedRead { index, .. } => write!(f, "read of unwritten cell {}", index),
+            RuntimeError::NonByteOutput { value, .. } => write!(f, "can't put {} as a character", value),
+            RuntimeError::BackwardJumpDisallowed { .. } => write!(f, "backward jump disallowed"),
+            RuntimeError::AssertionFailed { cell, .. } => write!(f, "assertion failed on cell {}", cell),
+            RuntimeError::Io { kind, .. } => write!(f, "I/O error ({})", kind),
         }
     }
 }
 
-/// Execute operations
-pub fn run<R: Read, W: Write, O: Runnable>(code: &Code<O>, env: &mut Environment<R, W>) {
-    let len_ops = code.ops.len();
-
-    while len_ops > env.pc {
-        code.ops[env.pc].run(&code, env);
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_message(f)?;
+        write!(f, " at pc {}", self.pc())?;
+        if let Some(offset) = self.source_offset() {
+            write!(f, " (source offset {})", offset)?;
+        }
+        Ok(())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::str::from_utf8;
-    use std::io::Cursor;
+impl std::error::Error for RuntimeError {}
 
-    const BUF_SIZE: usize = 1024;
-    const HELLO_BF: &'static str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+/// Describe the error as a diagnostic pointing at the operation in the source
+/// with a caret. The error's own source offset is used if it has one, or else
+/// the offset of its pc in the source map.
+pub fn format_error(err: &RuntimeError, source: &str, source_map: &[usize]) -> String {
+    let mut message = String::from("error: ");
+    err.write_message(&mut message).expect("Writing to a String can't fail");
 
-    #[test]
-    fn test_parse_ops() {
-        let source = "+-.,[><]".to_string();
-        let language = Language::default();
+    let offset = err.source_offset().or_else(|| source_map.get(err.pc()).copied());
+    let ch = offset.and_then(|offset| source.get(offset..)).and_then(|rest| rest.chars().next());
+    let (offset, ch) = match (offset, ch) {
+        (Some(offset), Some(ch)) => (offset, ch),
+        _ => return format!("{} at pc {}", message, err.pc()),
+    };
 
-        let result = parse(&source, &language);
+    let line_start = source[..offset].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = source[offset..].find('\n').map_or(source.len(), |index| offset + index);
+    let line_number = source[..line_start].matches('\n').count() + 1;
+    // Tabs are kept so the caret lines up however wide they're shown
+    let padding = source[line_start..offset].chars().map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+    let column = source[line_start..offset].chars().count() + 1;
+    let gutter = " ".repeat(line_number.to_string().len());
 
-        assert_eq!(result.ops, vec![
-            Op::Inc,
-            Op::Dec,
-            Op::PutChar,
-            Op::GetChar,
-            Op::LoopStart,
-            Op::IncPtr,
-            Op::DecPtr,
-            Op::LoopEnd,
-        ]);
-    }
+    format!(
+        "{} at line {}, column {} ('{}')\n{} |\n{} | {}\n{} | {}^",
+        message, line_number, column, ch, gutter, line_number, &source[line_start..line_end], gutter, padding,
+    )
+}
 
-    #[test]
-    fn test_parse_jumps() {
-        let source = "[+++]--[+[+]+]".to_string();
-        let language = Language::default();
+/// Execution environment.
+pub struct Environment<'a, R, W, T: ?Sized + Tape = [u8]> {
+    data: &'a mut T,
+    pc: usize,
+    pointer: usize,
+    max_pointer: usize,
+    // Number of cells the tape grew by in front, shifting indices of the others
+    grown_front: usize,
+    // Position the pointer started at, relative to the cells before any growth in front
+    start_position: isize,
+    reader: &'a mut R,
+    writer: &'a mut W,
+    options: RunOptions,
+    input_buffer: VecDeque<u8>,
+    steps: u64,
+    bytes_written: u64,
+    // Number of characters put, not counting other output such as the pointer's index
+    chars_put: u64,
+    bytes_read: u64,
+    // Whether each cell was written, tracked only when trapping uninitialized reads
+    written: Vec<bool>,
+    // Whether the pointer moved onto or over each cell and how many it did, tracked only
+    // when limiting cells
+    touched: Vec<bool>,
+    cells_touched: usize,
+    watchpoint: Option<(usize, WatchPredicate<T::Cell>)>,
+    // Writes in the current step, kept only by `run_with_memwatch`
+    write_log: Option<Vec<CellWrite<T::Cell>>>,
+}
 
-        let result = parse(&source, &language);
+/// Index, old data and new data of a cell written
+type CellWrite<C> = (usize, C, C);
 
-        assert_eq!(result.jump_table[0], 5);
-        assert_eq!(result.jump_table[4], 1);
+/// Predicate over old and new data of a watched cell
+type WatchPredicate<C> = Box<dyn Fn(C, C) -> bool>;
 
-        assert_eq!(result.jump_table[7], 14);
-        assert_eq!(result.jump_table[13], 8);
+impl<'a, R: Read, W: Write, T: ?Sized + Tape> Environment<'a, R, W, T> {
+    /// Fail with `RuntimeError::AssertionFailed` if data is zero
+    pub fn assert_nonzero(&self) -> Result<(), RuntimeError> {
+        if self.read_data_checked()? == T::Cell::ZERO {
+            return Err(RuntimeError::AssertionFailed { cell: self.pointer, pc: self.pc, source_offset: None });
+        }
+        Ok(())
+    }
 
-        assert_eq!(result.jump_table[9], 12);
-        assert_eq!(result.jump_table[11], 10);
+    /// Set data, as if it were written by the program
+    pub fn set_data(&mut self, value: T::Cell) -> Result<(), RuntimeError> {
+        self.write_data(value)
     }
 
-    #[test]
-    fn test_run() {
-        // hello.bf
-        let language = Language::default();
+    /// Add to data
+    pub fn add(&mut self, n: T::Cell) -> Result<(), RuntimeError> {
+        if let Some(modulus) = self.options.modulus.filter(|&m| m != 0) {
+            return self.add_modulo(modulus, n.to_u32() as i64);
+        }
 
-        let ops = parse(&HELLO_BF.to_string(), &language);
+        let data = self.data.get(self.pointer);
+        let value = match self.options.cell_overflow {
+            CellOverflow::Wrap => data.wrapping_add(n),
+            CellOverflow::Saturate => data.saturating_add(n),
+            CellOverflow::Error => data.checked_add(n)
+                .ok_or(RuntimeError::CellOverflow { pc: self.pc, source_offset: None })?,
+        };
+        self.write_data(value)
+    }
 
-        let mut data = [0; BUF_SIZE];
+    /// Sub from data
+    pub fn sub(&mut self, n: T::Cell) -> Result<(), RuntimeError> {
+        if let Some(modulus) = self.options.modulus.filter(|&m| m != 0) {
+            return self.add_modulo(modulus, -(n.to_u32() as i64));
+        }
+
+        let data = self.data.get(self.pointer);
+        let value = match self.options.cell_overflow {
+            CellOverflow::Wrap => data.wrapping_sub(n),
+            CellOverflow::Saturate => data.saturating_sub(n),
+            CellOverflow::Error => data.checked_sub(n)
+                .ok_or(RuntimeError::CellUnderflow { pc: self.pc, source_offset: None })?,
+        };
+        self.write_data(value)
+    }
+
+    /// Add to data as a number modulo the modulus, going out of range at zero and the modulus
+    fn add_modulo(&mut self, modulus: u32, delta: i64) -> Result<(), RuntimeError> {
+        let modulus = modulus as i64;
+        let value = self.data.get(self.pointer).to_u32() as i64 + delta;
+        let value = match self.options.cell_overflow {
+            CellOverflow::Wrap => value.rem_euclid(modulus),
+            CellOverflow::Saturate => value.clamp(0, modulus - 1),
+            CellOverflow::Error if value < 0 =>
+                return Err(RuntimeError::CellUnderflow { pc: self.pc, source_offset: None }),
+            CellOverflow::Error if value >= modulus =>
+                return Err(RuntimeError::CellOverflow { pc: self.pc, source_offset: None }),
+            CellOverflow::Error => value,
+        };
+        self.write_data(T::Cell::wrap_u32(value as u32))
+    }
+
+    /// Write data under the pointer. If it hits the watchpoint, the write isn't applied.
+    fn write_data(&mut self, value: T::Cell) -> Result<(), RuntimeError> {
+        if let Some((index, predicate)) = &self.watchpoint {
+            let old = self.data.get(self.pointer);
+            if *index == self.pointer && predicate(old, value) {
+                return Err(RuntimeError::Watchpoint {
+                    index: *index,
+                    old: old.to_u32(),
+                    new: value.to_u32(),
+                    pc: self.pc,
+                    source_offset: None,
+                });
+            }
+        }
+
+        if self.options.trap_uninitialized {
+            if self.written.len() <= self.pointer {
+                self.written.resize(self.pointer + 1, false);
+            }
+            self.written[self.pointer] = true;
+        }
+        if let Some(log) = &mut self.write_log {
+            log.push((self.pointer, self.data.get(self.pointer), value));
+        }
+        self.data.set(self.pointer, value);
+        Ok(())
+    }
+
+    /// Fail with `RuntimeError::Watchpoint` when writing to the cell at the index
+    /// and the predicate given the old and new data returns true
+    pub fn set_watchpoint(&mut self, index: usize, predicate: impl Fn(T::Cell, T::Cell) -> bool + 'static) {
+        self.watchpoint = Some((index, Box::new(predicate)));
+    }
+
+    /// Remove the watchpoint
+    pub fn clear_watchpoint(&mut self) {
+        self.watchpoint = None;
+    }
+
+    /// Add to pointer, growing the tape if it can grow
+    pub fn add_ptr(&mut self, n: usize) -> Result<(), RuntimeError> {
+        let from = self.pointer;
+        let pointer_max = self.data.len() - 1;
+        if pointer_max - self.pointer < n && self.data.grow_back(n - (pointer_max - self.pointer)) {
+            self.pointer += n;
+            self.max_pointer = self.max_pointer.max(self.pointer);
+            return self.touch(from..self.pointer + 1);
+        }
+
+        if self.options.pointer_policy == PointerPolicy::Error && pointer_max - self.pointer < n {
+            return Err(RuntimeError::PointerOverflow {
+                index: self.pointer.saturating_add(n),
+                tape_len: self.data.len(),
+                pc: self.pc,
+                source_offset: None,
+            });
+        }
+
+        // Avoiding overflow panic
+        self.pointer = if pointer_max >= n && pointer_max - n <= self.pointer {
+            pointer_max
+        } else {
+            min(pointer_max, self.pointer + n)
+        };
+        self.max_pointer = self.max_pointer.max(self.pointer);
+        self.touch(from..self.pointer + 1)
+    }
+
+    /// Sub from pointer, growing the tape if it can grow
+    pub fn sub_ptr(&mut self, n: usize) -> Result<(), RuntimeError> {
+        let from = self.pointer;
+        if self.pointer < n && self.data.grow_front(n - self.pointer) {
+            // Indices of existing cells shifted
+            let grown = n - self.pointer;
+            self.max_pointer += grown;
+            self.grown_front += grown;
+            if !self.written.is_empty() {
+                self.written.splice(0..0, std::iter::repeat_n(false, grown));
+            }
+            if !self.touched.is_empty() {
+                self.touched.splice(0..0, std::iter::repeat_n(false, grown));
+            }
+            if let Some((index, _)) = &mut self.watchpoint {
+                *index += grown;
+            }
+            if let Some(index) = &mut self.options.condition_cell {
+                *index += grown;
+            }
+            self.pointer = 0;
+            return self.touch(0..from + grown + 1);
+        }
+
+        if self.options.pointer_policy == PointerPolicy::Error && self.pointer < n {
+            let index = (self.pointer as isize).saturating_sub_unsigned(n);
+            return Err(RuntimeError::PointerUnderflow { index, pc: self.pc, source_offset: None });
+        }
+
+        // Avoiding underflow panic
+        self.pointer = self.pointer.saturating_sub(n);
+        self.touch(self.pointer..from + 1)
+    }
+
+    /// Count cells at the indices as touched, failing with
+    /// `RuntimeError::MemoryLimitExceeded` at the first new one over the limit
+    fn touch(&mut self, indices: Range<usize>) -> Result<(), RuntimeError> {
+        let limit = match self.options.max_cells {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.touched.len() < indices.end {
+            self.touched.resize(indices.end, false);
+        }
+        for index in indices {
+            if !self.touched[index] {
+                if self.cells_touched >= limit {
+                    return Err(RuntimeError::MemoryLimitExceeded { limit, pc: self.pc, source_offset: None });
+                }
+                self.touched[index] = true;
+                self.cells_touched += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Set what to do when the pointer moves outside of the tape
+    pub fn set_pointer_policy(&mut self, pointer_policy: PointerPolicy) {
+        self.options.pointer_policy = pointer_policy;
+    }
+
+    /// Set whether loop ends fail instead of jumping back
+    pub fn set_forward_only(&mut self, forward_only: bool) {
+        self.options.forward_only = forward_only;
+    }
+
+    /// Set the cell loops test instead of the cell under the pointer, if any
+    pub fn set_condition_cell(&mut self, condition_cell: Option<usize>) {
+        self.options.condition_cell = condition_cell;
+    }
+
+    /// Print data under the pointer as a character
+    pub fn put_char(&mut self) -> Result<(), RuntimeError> {
+        let data = self.read_data_checked()?;
+
+        let raw_char = match (self.options.output_format, self.options.io_width) {
+            (OutputFormat::Raw, IoWidth::Byte) => Some(self.wide_char(data.to_u32())?),
+            _ => None,
+        };
+        let len = match (self.options.output_format, raw_char) {
+            (_, Some(ch)) => ch.len_utf8() as u64,
+            (OutputFormat::Raw, None) => 2,
+            (OutputFormat::Decimal, _) => {
+                let digits = data.to_u32().checked_ilog10().unwrap_or(0) as usize + 1;
+                (digits.max(self.options.decimal_width.unwrap_or(0)) + self.options.decimal_separator.len()) as u64
+            }
+            (OutputFormat::Hex, _) => (data.to_u32().checked_ilog2().unwrap_or(0) as u64 / 4 + 1).max(2) + 1,
+        };
+        if self.options.max_output.is_some_and(|limit| self.bytes_written + len > limit) {
+            return Err(RuntimeError::OutputLimitExceeded { pc: self.pc, source_offset: None });
+        }
+        self.bytes_written += len;
+        self.chars_put += 1;
+
+        let written = match (self.options.output_format, raw_char) {
+            (_, Some(ch)) => write!(self.writer, "{}", ch),
+            (OutputFormat::Raw, None) => self.writer.write_all(&(data.to_u32() as u16).to_le_bytes()),
+            (OutputFormat::Decimal, _) => write!(
+                self.writer, "{:0width$}{}", data.to_u32(), self.options.decimal_separator,
+                width = self.options.decimal_width.unwrap_or(0),
+            ),
+            (OutputFormat::Hex, _) => write!(self.writer, "{:02x} ", data.to_u32()),
+        };
+        written.map_err(|err| self.io_error(err))?;
+        if self.options.flush_policy == FlushPolicy::PerByte {
+            self.writer.flush().map_err(|err| self.io_error(err))?;
+        }
+        Ok(())
+    }
+
+    /// Character to put raw data as, following the policy for data wider than a byte
+    fn wide_char(&self, value: u32) -> Result<char, RuntimeError> {
+        let error = RuntimeError::NonByteOutput { value, pc: self.pc, source_offset: None };
+        match self.options.wide_char_policy {
+            _ if value <= u32::from(u8::MAX) => Ok(value as u8 as char),
+            WideCharPolicy::Truncate => Ok(value as u8 as char),
+            WideCharPolicy::Error => Err(error),
+            WideCharPolicy::Utf8 => char::from_u32(value).ok_or(error),
+        }
+    }
+
+    /// Write the position of the pointer as decimal text like `@5 `. It's relative
+    /// to the first cell as the tape started, so it goes negative like `@-2 ` if the
+    /// tape grew in front.
+    pub fn put_pointer(&mut self) -> Result<(), RuntimeError> {
+        let text = format!("@{} ", self.pointer as isize - self.grown_front as isize);
+        let len = text.len() as u64;
+        if self.options.max_output.is_some_and(|limit| self.bytes_written + len > limit) {
+            return Err(RuntimeError::OutputLimitExceeded { pc: self.pc, source_offset: None });
+        }
+        self.bytes_written += len;
+
+        self.writer.write_all(text.as_bytes()).map_err(|err| self.io_error(err))?;
+        if self.options.flush_policy == FlushPolicy::PerByte {
+            self.writer.flush().map_err(|err| self.io_error(err))?;
+        }
+        Ok(())
+    }
+
+    fn io_error(&self, err: std::io::Error) -> RuntimeError {
+        RuntimeError::Io { kind: err.kind(), pc: self.pc, source_offset: None }
+    }
+
+    /// Read a character into data.
+    /// The reader is read a byte at a time and never past the bytes read, so wrap
+    /// unbuffered readers such as `File` in a `BufReader` for speed.
+    pub fn read_char(&mut self) -> Result<(), RuntimeError> {
+        let eof_mode = self.options.eof_mode;
+        let value = match (self.read_byte()?, self.options.io_width) {
+            (Some(byte), IoWidth::Byte) => byte as u32,
+            (Some(low), IoWidth::Word) => {
+                let high = match (self.read_byte()?, eof_mode) {
+                    (Some(byte), _) => byte,
+                    (None, EofMode::Zero) => 0,
+                    (None, EofMode::Unchanged) => (self.data.get(self.pointer).to_u32() >> 8) as u8,
+                    (None, EofMode::Max) => u8::MAX,
+                };
+                u32::from(u16::from_le_bytes([low, high]))
+            }
+            (None, _) => return match eof_mode {
+                EofMode::Zero => self.write_data(T::Cell::ZERO),
+                EofMode::Unchanged => Ok(()),
+                EofMode::Max => self.write_data(T::Cell::MAX),
+            },
+        };
+        self.write_data(T::Cell::wrap_u32(value))
+    }
+
+    /// Read a byte of input, echoing it if asked to
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        if self.options.max_input.is_some_and(|limit| self.bytes_read >= limit) {
+            return Err(RuntimeError::InputLimitExceeded { pc: self.pc, source_offset: None });
+        }
+
+        let char = match self.input_buffer.pop_front() {
+            Some(byte) => Some(byte),
+            None if self.options.input_availability == InputAvailability::Immediate => None,
+            None => {
+                // Flush first so that prompts show up before blocking on the reader
+                if self.options.flush_policy != FlushPolicy::PerByte {
+                    self.writer.flush().map_err(|err| self.io_error(err))?;
+                }
+                let mut buf = [0u8; 1];
+                match self.reader.read_exact(&mut buf) {
+                    Ok(()) => Some(buf[0]),
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+                    Err(err) => return Err(self.io_error(err)),
+                }
+            }
+        };
+        if char.is_some() {
+            self.bytes_read += 1;
+        }
+        if let (Some(byte), true) = (char, self.options.echo_input) {
+            self.writer.write_all(&[byte]).map_err(|err| self.io_error(err))?;
+            if self.options.flush_policy == FlushPolicy::PerByte {
+                self.writer.flush().map_err(|err| self.io_error(err))?;
+            }
+        }
+        Ok(char)
+    }
+
+    /// Append bytes to the preloaded input, which is consumed before the reader
+    pub fn preload_input(&mut self, input: &[u8]) {
+        self.input_buffer.extend(input);
+    }
+
+    /// Set how reading a character waits for input
+    pub fn set_input_availability(&mut self, input_availability: InputAvailability) {
+        self.options.input_availability = input_availability;
+    }
+
+    /// Set whether to write characters read to the writer
+    pub fn set_echo_input(&mut self, echo_input: bool) {
+        self.options.echo_input = echo_input;
+    }
+
+    /// Finish using the environment, flushing the tape to any storage behind it
+    /// and anything still buffered in the writer
+    pub fn finish(self) -> std::io::Result<()> {
+        self.data.flush()?;
+        self.writer.flush()
+    }
+
+    /// Count a step, failing if the step limit is exceeded
+    pub fn count_step(&mut self) -> Result<(), RuntimeError> {
+        if let Some(limit) = self.options.step_limit {
+            if self.steps >= limit {
+                return Err(RuntimeError::StepLimitExceeded { pc: self.pc, source_offset: None });
+            }
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Number of operations run so far
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Number of bytes written by putting characters so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Number of bytes consumed by reading characters so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Increment program pointer
+    pub fn advance_pc(&mut self) {
+        self.pc += 1;
+    }
+
+    /// Set program counter
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    /// Move the program counter and pointer back to where they started, keeping data on the tape
+    pub fn rewind(&mut self) {
+        self.pc = 0;
+        self.pointer = (self.start_position + self.grown_front as isize) as usize;
+    }
+
+    /// Read data under the pointer
+    pub fn read_data(&self) -> T::Cell {
+        self.data.get(self.pointer)
+    }
+
+    /// Read data under the pointer, failing with `RuntimeError::UninitializedRead`
+    /// if it was never written when trapping uninitialized reads
+    pub fn read_data_checked(&self) -> Result<T::Cell, RuntimeError> {
+        if self.options.trap_uninitialized && !self.written.get(self.pointer).copied().unwrap_or(false) {
+            return Err(RuntimeError::UninitializedRead { index: self.pointer, pc: self.pc, source_offset: None });
+        }
+        Ok(self.read_data())
+    }
+
+    /// Read data of the cell loops test, which is the condition cell if there is
+    /// one, or else the cell under the pointer. A condition cell off the tape reads as zero.
+    pub fn read_condition_checked(&self) -> Result<T::Cell, RuntimeError> {
+        let index = match self.options.condition_cell {
+            Some(index) => index,
+            None => return self.read_data_checked(),
+        };
+        if self.options.trap_uninitialized && !self.written.get(index).copied().unwrap_or(false) {
+            return Err(RuntimeError::UninitializedRead { index, pc: self.pc, source_offset: None });
+        }
+        Ok(self.cell(index).unwrap_or(T::Cell::ZERO))
+    }
+
+    /// Read data of the cell at the index, if it's on the tape
+    pub fn cell(&self, index: usize) -> Option<T::Cell> {
+        if index < self.data.len() { Some(self.data.get(index)) } else { None }
+    }
+
+    /// Current program counter
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Current index of the pointer
+    pub fn pointer(&self) -> usize {
+        self.pointer
+    }
+
+    /// Move the pointer to the index, failing if it's outside of the tape. Before
+    /// any step has run, this is also where `rewind` moves the pointer back to.
+    pub fn set_pointer(&mut self, index: usize) -> Result<(), RuntimeError> {
+        if index >= self.data.len() {
+            return Err(RuntimeError::PointerOverflow { index, tape_len: self.data.len(), pc: self.pc, source_offset: None });
+        }
+        if self.steps == 0 {
+            self.start_position = index as isize - self.grown_front as isize;
+        }
+        self.pointer = index;
+        self.max_pointer = self.max_pointer.max(index);
+        self.touch(index..index + 1)
+    }
+
+    /// Write cells in the range as hex, 16 cells per line prefixed with the
+    /// index of the first one. Defaults to cells up to the furthest the pointer has reached.
+    pub fn dump_tape(&self, out: &mut impl Write, range: Option<Range<usize>>) -> std::io::Result<()> {
+        let range = range.unwrap_or(0..self.max_pointer + 1);
+        let end = min(range.end, self.data.len());
+        let start = min(range.start, end);
+
+        for row_start in (start..end).step_by(16) {
+            write!(out, "{:08x}:", row_start)?;
+            for index in row_start..min(row_start + 16, end) {
+                write!(out, " {:0width$x}", self.data.get(index), width = T::Cell::BITS as usize / 4)?;
+            }
+            writeln!(out)?;
+        }
+        Ok(())
+    }
+
+    /// Lay out data of cells in the range as a grid `width` cells wide in decimal,
+    /// each row prefixed with the index of its first cell and the cell under the
+    /// pointer in brackets. The range is clipped to the tape.
+    pub fn format_tape_grid(&self, width: usize, range: Range<usize>) -> String {
+        let end = min(range.end, self.data.len());
+        let start = min(range.start, end);
+        let cell_width = T::Cell::MAX.to_string().len();
+        let index_width = start.max(end.saturating_sub(1)).to_string().len();
+
+        let mut grid = String::new();
+        for row_start in (start..end).step_by(width.max(1)) {
+            let mut row = format!("{:>width$}:", row_start, width = index_width);
+            for index in row_start..min(row_start + width.max(1), end) {
+                let data = self.data.get(index);
+                if index == self.pointer {
+                    row.push_str(&format!("[{:>width$}]", data, width = cell_width));
+                } else {
+                    row.push_str(&format!(" {:>width$} ", data, width = cell_width));
+                }
+            }
+            grid.push_str(row.trim_end());
+            grid.push('\n');
+        }
+        grid
+    }
+
+    /// Replace the options to run with
+    pub fn set_options(&mut self, options: RunOptions) {
+        self.options = options;
+    }
+
+    /// Make an environment over any tape
+    pub fn with_tape(data: &'a mut T, reader: &'a mut R, writer: &'a mut W, options: RunOptions) -> Self {
+        Environment {
+            data,
+            writer,
+            reader,
+            pointer: 0,
+            max_pointer: 0,
+            grown_front: 0,
+            start_position: 0,
+            pc: 0,
+            options,
+            input_buffer: VecDeque::new(),
+            steps: 0,
+            bytes_written: 0,
+            chars_put: 0,
+            bytes_read: 0,
+            written: Vec::new(),
+            touched: Vec::new(),
+            cells_touched: 0,
+            watchpoint: None,
+            write_log: None,
+        }
+    }
+}
+
+impl<'a, R: Read, W: Write, C: Cell> Environment<'a, R, W, [C]> {
+    /// Make an environment over a tape of cells of any width
+    pub fn with_cells(data: &'a mut [C], reader: &'a mut R, writer: &'a mut W, options: RunOptions) -> Self {
+        Self::with_tape(data, reader, writer, options)
+    }
+}
+
+impl<'a, R: Read, W: Write> Environment<'a, R, W> {
+    /// Make an environment over the tape. Any memory works as the tape,
+    /// such as a memory-mapped file (see `MmapTape` with the `memmap` feature).
+    pub fn new(data: &'a mut [u8], reader: &'a mut R, writer: &'a mut W) -> Self {
+        Self::with_options(data, reader, writer, RunOptions::default())
+    }
+
+    pub fn with_options(data: &'a mut [u8], reader: &'a mut R, writer: &'a mut W, options: RunOptions) -> Self {
+        Self::with_cells(data, reader, writer, options)
+    }
+
+    /// Make an environment over the tape with the pointer starting at the index
+    pub fn with_pointer(data: &'a mut [u8], reader: &'a mut R, writer: &'a mut W, pointer: usize) -> Result<Self, RuntimeError> {
+        let mut env = Self::new(data, reader, writer);
+        env.set_pointer(pointer)?;
+        Ok(env)
+    }
+}
+
+/// Executable brainfuck operations
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Code<T> {
+    ops: Vec<T>,
+    jump_table: Vec<usize>,
+    source_map: Option<Vec<usize>>,
+}
+
+impl<T> Code<T> {
+    /// Whether there are no operations, as for a source of only comments
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Byte offsets in the source for each operation, if recorded while parsing
+    pub fn source_map(&self) -> Option<&[usize]> {
+        self.source_map.as_deref()
+    }
+
+    /// Byte offset in the source of the operation at `pc`, if recorded
+    pub fn source_offset(&self, pc: usize) -> Option<usize> {
+        self.source_map.as_ref().and_then(|map| map.get(pc).copied())
+    }
+}
+
+impl<T: StableEncoding> Code<T> {
+    /// 64-bit FNV-1a hash of the op count (`u64`) followed by the stable encoding
+    /// of each operation, which is the same across runs, platforms and compilers.
+    /// The jump table and source map don't affect it.
+    pub fn fingerprint(&self) -> u64 {
+        let mut bytes = (self.ops.len() as u64).to_le_bytes().to_vec();
+        for op in self.ops.iter() {
+            op.encode(&mut bytes);
+        }
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+    }
+}
+
+impl Code<CompressedOp> {
+    /// Emit run-length encoded source in the language, in the syntax read by
+    /// `expand_rle`. Runs of one are emitted as the bare token. Returns None if
+    /// a digit is a token of the language, as counts would read as tokens.
+    pub fn to_rle_source(&self, language: &Language) -> Option<String> {
+        if language.has_digit_token() {
+            return None;
+        }
+
+        fn run(source: &mut String, count: usize, token: char) {
+            match count {
+                0 => (),
+                1 => source.push(token),
+                _ => {
+                    source.push_str(&count.to_string());
+                    source.push(token);
+                }
+            }
+        }
+
+        let mut source = String::new();
+
+        for op in self.ops.iter() {
+            match *op {
+                CompressedOp::Add(n) => run(&mut source, n as usize, language.inc),
+                CompressedOp::Sub(n) => run(&mut source, n as usize, language.dec),
+                CompressedOp::Forward(n) => run(&mut source, n, language.inc_ptr),
+                CompressedOp::Back(n) => run(&mut source, n, language.dec_ptr),
+                CompressedOp::PutChar => source.push(language.put_char),
+                CompressedOp::GetChar => source.push(language.get_char),
+                CompressedOp::LoopStart | CompressedOp::IfNonzero => source.push(language.loop_start),
+                CompressedOp::LoopEnd | CompressedOp::EndIf => source.push(language.loop_end),
+                CompressedOp::SetZero => {
+                    source.push(language.loop_start);
+                    source.push(language.dec);
+                    source.push(language.loop_end);
+                }
+                CompressedOp::SetValue(n) => {
+                    source.push(language.loop_start);
+                    source.push(language.dec);
+                    source.push(language.loop_end);
+                    run(&mut source, n as usize, language.inc);
+                }
+                // Dropped for languages without the instruction
+                CompressedOp::Assert => source.extend(language.assert),
+                CompressedOp::PutPointer => source.extend(language.put_pointer),
+            }
+        }
+
+        Some(source)
+    }
+}
+
+/// Errors found while validating code.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ValidationError {
+    /// The jump table doesn't have an entry for each operation.
+    JumpTableLength { ops: usize, jump_table: usize },
+    /// Loop start without matching loop end.
+    UnmatchedLoopStart { pc: usize },
+    /// Loop end without matching loop start.
+    UnmatchedLoopEnd { pc: usize },
+    /// Jump table entry of a loop doesn't point past its matching bracket.
+    BadJump { pc: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::JumpTableLength { ops, jump_table } =>
+                write!(f, "jump table has {} entries for {} operations", jump_table, ops),
+            ValidationError::UnmatchedLoopStart { pc } => write!(f, "unmatched loop start at pc {}", pc),
+            ValidationError::UnmatchedLoopEnd { pc } => write!(f, "unmatched loop end at pc {}", pc),
+            ValidationError::BadJump { pc } => write!(f, "bad jump at pc {}", pc),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Errors occurring while running code that is validated first.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CheckedRunError {
+    /// Code isn't runnable, so nothing ran.
+    Invalid(ValidationError),
+    /// Running the valid code failed.
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for CheckedRunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheckedRunError::Invalid(err) => write!(f, "invalid code: {}", err),
+            CheckedRunError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CheckedRunError {}
+
+impl<T: Bracket + Clone> Code<T> {
+    /// Code running this code, then the other. Jumps of the other are moved past
+    /// this code's operations. Source maps are kept only if both have one, with
+    /// offsets of the other's operations moved past the end of this code's last one.
+    pub fn concat(&self, other: &Code<T>) -> Code<T> {
+        let shift = self.ops.len();
+        let mut ops = Vec::with_capacity(shift + other.ops.len());
+        ops.extend_from_slice(&self.ops);
+        ops.extend_from_slice(&other.ops);
+
+        let mut jump_table = Vec::with_capacity(ops.len());
+        jump_table.extend_from_slice(&self.jump_table);
+        jump_table.extend(other.ops.iter().zip(other.jump_table.iter()).map(|(op, &jump)| {
+            if op.is_loop_start() || op.is_loop_end() { jump + shift } else { jump }
+        }));
+
+        let source_map = match (&self.source_map, &other.source_map) {
+            (Some(map), Some(other_map)) => {
+                let start = map.last().map_or(0, |&offset| offset + 1);
+                Some(map.iter().copied().chain(other_map.iter().map(|&offset| offset + start)).collect())
+            }
+            _ => None,
+        };
+
+        Code { ops, jump_table, source_map }
+    }
+}
+
+impl<T: Bracket> Code<T> {
+    /// Check that loops are balanced and the jump table matches them,
+    /// so running the code can't jump out of bounds
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        if self.ops.len() != self.jump_table.len() {
+            return Err(ValidationError::JumpTableLength { ops: self.ops.len(), jump_table: self.jump_table.len() });
+        }
+
+        let mut map_stack = Vec::new();
+
+        for (pc, op) in self.ops.iter().enumerate() {
+            if op.is_loop_start() {
+                map_stack.push(pc);
+            } else if op.is_loop_end() {
+                let begin = map_stack.pop().ok_or(ValidationError::UnmatchedLoopEnd { pc })?;
+                if self.jump_table[begin] != pc + 1 {
+                    return Err(ValidationError::BadJump { pc: begin });
+                }
+                if self.jump_table[pc] != begin + 1 {
+                    return Err(ValidationError::BadJump { pc });
+                }
+            }
+        }
+
+        match map_stack.pop() {
+            Some(pc) => Err(ValidationError::UnmatchedLoopStart { pc }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Errors occurring while parsing with limits.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    /// Loops are nested deeper than the limit.
+    NestingTooDeep { depth: usize, limit: usize },
+    /// Loop start at the byte offset is never ended.
+    UnmatchedLoopStart { offset: usize },
+    /// Loop end at the byte offset has no loop start.
+    UnmatchedLoopEnd { offset: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::NestingTooDeep { depth, limit } =>
+                write!(f, "loops nested {} deep exceed the limit of {}", depth, limit),
+            ParseError::UnmatchedLoopStart { offset } => write!(f, "unmatched loop start at offset {}", offset),
+            ParseError::UnmatchedLoopEnd { offset } => write!(f, "unmatched loop end at offset {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Check that loops in source code are balanced without parsing it.
+/// An extra loop end is reported at its byte offset, and otherwise the
+/// earliest loop start that is never ended.
+pub fn check_brackets(source: &str, language: &Language) -> Result<(), ParseError> {
+    let mut depth = 0usize;
+    // Unended loop starts are the last ones to open each depth, so the outermost is the earliest
+    let mut outermost_start = 0;
+
+    for (offset, ch) in language.token_indices(source) {
+        if ch == language.loop_start {
+            if depth == 0 {
+                outermost_start = offset;
+            }
+            depth += 1;
+        } else if ch == language.loop_end {
+            depth = depth.checked_sub(1).ok_or(ParseError::UnmatchedLoopEnd { offset })?;
+        }
+    }
+
+    if depth > 0 {
+        Err(ParseError::UnmatchedLoopStart { offset: outermost_start })
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse source code into the operations
+pub fn parse(source: &str, language: &Language) -> Code<Op> {
+    parse_inner(language.token_indices(source), language, false, None, false).expect("Unmatched loop end")
+}
+
+/// Parse source code into the operations, recording the byte offset of each
+/// operation in the source
+pub fn parse_with_source_map(source: &str, language: &Language) -> Code<Op> {
+    parse_inner(language.token_indices(source), language, true, None, false).expect("Unmatched loop end")
+}
+
+/// Parse source code into the operations, rejecting loops nested deeper than `max_depth`.
+/// Unmatched loops fail as in `check_brackets` instead of panicking.
+pub fn parse_with_limits(source: &str, language: &Language, max_depth: usize) -> Result<Code<Op>, ParseError> {
+    parse_inner(language.token_indices(source), language, false, Some(max_depth), true)
+}
+
+/// Parse source code read a line at a time into the operations, recording their
+/// byte offsets, so the whole source is never held in memory. Fails if reading fails, or with `InvalidData`
+/// if the source isn't UTF-8 or a loop end is unmatched.
+pub fn parse_reader<R: BufRead>(mut reader: R, language: &Language) -> std::io::Result<Code<Op>> {
+    let mut state = TokenState::default();
+    let mut error = None;
+    let mut line = String::new();
+    let mut line_offset = 0;
+
+    let token_chars = std::iter::from_fn(|| {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(len) => {
+                let start = line_offset;
+                line_offset += len;
+                let tokens = line.char_indices()
+                    .filter(|&(index, ch)| state.accept(language, ch, &line[index..]))
+                    .map(|(index, ch)| (start + index, ch))
+                    .collect::<Vec<_>>();
+                Some(tokens)
+            }
+            Err(err) => {
+                error = Some(err);
+                None
+            }
+        }
+    }).flatten();
+
+    let code = parse_inner(token_chars, language, true, None, false);
+    match (error, code) {
+        (Some(err), _) => Err(err),
+        (None, Ok(code)) => Ok(code),
+        (None, Err(err)) => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+    }
+}
+
+/// Parse source code straight into compressed operations for `u8` cells, giving the
+/// same code as `compress(&parse(source, language))` without making the operations
+/// in between. Unmatched loops fail as in `check_brackets` instead of panicking.
+pub fn parse_compressed(source: &str, language: &Language) -> Result<Code<CompressedOp>, ParseError> {
+    let mut ops = Vec::new();
+    let mut jump_table = Vec::new();
+    // Indices and byte offsets of loop starts not ended yet
+    let mut map_stack: Vec<(usize, usize)> = Vec::new();
+    // Last operation with its count and the byte offset of its first token, not pushed yet
+    let mut group: Option<(Op, usize, usize)> = None;
+
+    let mut push_group = |ops: &mut Vec<CompressedOp>, (op, count, offset): (Op, usize, usize)| {
+        let pc = ops.len();
+        jump_table.push(0);
+        match op {
+            Op::LoopStart => {
+                ops.push(CompressedOp::LoopStart);
+                map_stack.push((pc, offset));
+            }
+            Op::LoopEnd => {
+                ops.push(CompressedOp::LoopEnd);
+                let (begin, _) = map_stack.pop().ok_or(ParseError::UnmatchedLoopEnd { offset })?;
+                jump_table[begin] = pc + 1;
+                jump_table[pc] = begin + 1;
+            }
+            _ => ops.push(compress_group::<u8>(op, count)),
+        }
+        Ok(())
+    };
+
+    let config = CompressConfig::default();
+    for (offset, ch) in language.token_indices(source) {
+        let op = match language.token_op(ch) {
+            Some(Op::Nop) | None => continue,
+            Some(op) => op,
+        };
+        match &mut group {
+            Some((last, count, _)) if *last == op && config.is_repeatable(op) => *count += 1,
+            _ => {
+                if let Some(last) = group.replace((op, 1, offset)) {
+                    push_group(&mut ops, last)?;
+                }
+            }
+        }
+    }
+    if let Some(last) = group {
+        push_group(&mut ops, last)?;
+    }
+
+    // Unended loop starts are the last ones to open each depth, so the outermost is the earliest
+    if let Some(&(_, offset)) = map_stack.first() {
+        return Err(ParseError::UnmatchedLoopStart { offset });
+    }
+    Ok(Code { ops, jump_table, source_map: None })
+}
+
+/// Parse tokens with their byte offsets, failing on unmatched loop ends and, if
+/// rejecting them, loop starts never ended
+fn parse_inner(token_chars: impl Iterator<Item = (usize, char)>, language: &Language, record_source_map: bool, max_depth: Option<usize>, reject_unended: bool) -> Result<Code<Op>, ParseError> {
+
+    // Grown as tokens are found, so nothing is allocated for programs without any
+    let mut ops = Vec::new();
+    let mut jump_table = Vec::new();
+    let mut map_stack = Vec::new();
+    let mut source_map = Vec::new();
+
+    for (pc, (offset, char)) in token_chars.enumerate() {
+        if record_source_map {
+            source_map.push(offset);
+        }
+        jump_table.push(0);
+
+        match language.token_op(char) {
+            Some(Op::LoopStart) => {
+                ops.push(Op::LoopStart);
+                map_stack.push((pc, offset));
+                if let Some(limit) = max_depth {
+                    if map_stack.len() > limit {
+                        return Err(ParseError::NestingTooDeep { depth: map_stack.len(), limit });
+                    }
+                }
+            }
+            Some(Op::LoopEnd) => {
+                ops.push(Op::LoopEnd);
+                let (begin, _) = map_stack.pop().ok_or(ParseError::UnmatchedLoopEnd { offset })?;
+                jump_table[begin] = pc + 1;
+                jump_table[pc] = begin + 1;
+            }
+            Some(op) => ops.push(op),
+            None => ()
+        }
+    }
+
+    if reject_unended {
+        // Unended loop starts are the last ones to open each depth, so the outermost is the earliest
+        if let Some(&(_, offset)) = map_stack.first() {
+            return Err(ParseError::UnmatchedLoopStart { offset });
+        }
+    }
+    Ok(Code { ops, jump_table, source_map: if record_source_map { Some(source_map) } else { None } })
+}
+
+/// Where tokenizing is up to, so source can be tokenized a line at a time
+#[derive(Debug, Copy, Clone)]
+struct TokenState {
+    in_comment: bool,
+    in_expectation: bool,
+    line_start: bool,
+}
+
+impl Default for TokenState {
+    fn default() -> Self {
+        TokenState { in_comment: false, in_expectation: false, line_start: true }
+    }
+}
+
+impl TokenState {
+    /// Whether the character is a token, given the rest of its line starting with it
+    fn accept(&mut self, language: &Language, ch: char, rest: &str) -> bool {
+        let at_line_start = self.line_start;
+        self.line_start = ch == '\n';
+        if self.in_expectation && ch != '\n' {
+            false
+        } else if self.in_expectation {
+            // The line break after an expectation may itself be a token
+            self.in_expectation = false;
+            language.is_token(ch)
+        } else if self.in_comment {
+            self.in_comment = Some(ch) != language.comment_close;
+            false
+        } else if language.expectations && at_line_start && rest.starts_with(EXPECTATION_PREFIX) {
+            self.in_expectation = ch != '\n';
+            false
+        } else if Some(ch) == language.comment_open {
+            self.in_comment = true;
+            false
+        } else {
+            language.is_token(ch)
+        }
+    }
+}
+
+/// Lines starting with this carry output the program is expected to write
+const EXPECTATION_PREFIX: &str = "-- expect:";
+
+/// Expected outputs written on lines starting with `-- expect:`, in order.
+/// The text after the prefix is taken as is, without surrounding whitespace.
+/// Parsing skips these lines only for languages made `with_expectations`.
+pub fn extract_expectations(source: &str) -> Vec<String> {
+    source.lines()
+        .filter_map(|line| line.strip_prefix(EXPECTATION_PREFIX))
+        .map(|expected| expected.trim().to_owned())
+        .collect()
+}
+
+/// Split source on the first `!` into the program and input for it.
+/// Without `!` the whole source is the program and the input is empty.
+pub fn split_program_input(source: &str) -> (&str, &str) {
+    match source.find('!') {
+        Some(index) => (&source[..index], &source[index + 1..]),
+        None => (source, ""),
+    }
+}
+
+/// Expand run-length encoded source. A decimal count immediately followed by a
+/// token repeats the token that many times, so `10+` is the same as `++++++++++`.
+/// Counts not followed by a token are left as they are. Returns None if a digit is
+/// a token of the language, as counts would be ambiguous. Byte offsets in the
+/// expanded source, such as of parse and runtime errors, don't map back to `source`.
+pub fn expand_rle(source: &str, language: &Language) -> Option<String> {
+    if language.has_digit_token() {
+        return None;
+    }
+
+    let mut expanded = String::with_capacity(source.len());
+    let mut digits = String::new();
+
+    for ch in source.chars() {
+        if ch.is_ascii_digit() {
+            digits.push(ch);
+            continue;
+        }
+
+        if language.is_token(ch) && !digits.is_empty() {
+            match digits.parse::<usize>() {
+                Ok(count) => expanded.extend(std::iter::repeat_n(ch, count)),
+                Err(_) => {
+                    expanded.push_str(&digits);
+                    expanded.push(ch);
+                }
+            }
+        } else {
+            expanded.push_str(&digits);
+            expanded.push(ch);
+        }
+        digits.clear();
+    }
+    expanded.push_str(&digits);
+
+    Some(expanded)
+}
+
+/// Guess which of the candidates the source is written in. Picks the candidate
+/// under which loops are balanced and the most characters are tokens.
+pub fn detect_language<'a>(source: &str, candidates: &'a [Language]) -> Option<&'a Language> {
+    let total = source.chars().count();
+    if total == 0 {
+        return None;
+    }
+
+    let mut best: Option<(&Language, f64)> = None;
+    for language in candidates {
+        // Brackets are counted as parsing reads them, skipping comments and expectations
+        if check_brackets(source, language).is_err() {
+            continue;
+        }
+
+        let tokens = language.token_indices(source).count();
+        let density = tokens as f64 / total as f64;
+        if tokens > 0 && best.is_none_or(|(_, best_density)| density > best_density) {
+            best = Some((language, density));
+        }
+    }
+
+    best.map(|(language, _)| language)
+}
+
+/// Compress operations for running over `u8` cells
+pub fn compress(code: &Code<Op>) -> Code<CompressedOp> {
+    compress_for::<u8>(code)
+}
+
+/// Which runs of operations compression merges into one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CompressConfig {
+    /// Merge runs of additions and subtractions
+    pub merge_data: bool,
+    /// Merge runs of pointer moves
+    pub merge_ptr: bool,
+}
+
+impl Default for CompressConfig {
+    fn default() -> Self {
+        CompressConfig { merge_data: true, merge_ptr: true }
+    }
+}
+
+impl CompressConfig {
+    /// Whether runs of the operation merge into one
+    fn is_repeatable(&self, op: Op) -> bool {
+        match op {
+            Op::Inc | Op::Dec => self.merge_data,
+            Op::IncPtr | Op::DecPtr => self.merge_ptr,
+            _ => false,
+        }
+    }
+}
+
+/// Compress operations for running over cells of type `C`.
+/// Runs of additions and subtractions are wrapped around the range of `C`.
+pub fn compress_for<C: Cell>(code: &Code<Op>) -> Code<CompressedOp> {
+    compress_with::<C>(code, &CompressConfig::default())
+}
+
+/// Compress operations for running over cells of type `C`, merging only the
+/// runs the config enables
+pub fn compress_with<C: Cell>(code: &Code<Op>, config: &CompressConfig) -> Code<CompressedOp> {
+    let mut last_op: Option<Op> = None;
+    let mut count: usize = 1;
+    let mut pc = 0;
+    let mut map_stack = Vec::new();
+    let mut group_start = 0;
+    let mut op_groups: Vec<(Op, usize)> = Vec::with_capacity(code.ops.len());
+    let mut group_starts = Vec::with_capacity(code.ops.len());
+
+    for (i, op) in code.ops.iter().enumerate() {
+        // Dropped, so ops around it group as if it weren't there
+        if *op == Op::Nop {
+            continue;
+        }
+        if let Some(last_op_) = last_op {
+            if last_op_ == *op && config.is_repeatable(last_op_) {
+                // Can't overflow, as a group is never longer than the code
+                count += 1;
+            } else {
+                op_groups.push((last_op_, count));
+                group_starts.push(group_start);
+
+                last_op = Some(*op);
+                count = 1;
+                group_start = i;
+            }
+        } else {
+            last_op = Some(*op);
+        }
+    }
+
+    if let Some(last_op_) = last_op {
+        op_groups.push((last_op_, count));
+        group_starts.push(group_start);
+    }
+
+    // Nothing grouped, so the jump table and source map carry over as they are
+    if op_groups.len() == code.ops.len() {
+        let ops = op_groups.into_iter().map(|(op, count)| compress_group::<C>(op, count)).collect();
+        return Code { ops, jump_table: code.jump_table.clone(), source_map: code.source_map.clone() };
+    }
+
+    let mut compressed_ops = Vec::with_capacity(op_groups.len());
+    let mut jump_table = vec![0; op_groups.len()];
+
+    // Each compressed operation maps to the source of the first operation in its group
+    let source_map = code.source_map.as_ref().map(|map| {
+        group_starts.iter().map(|&start| map[start]).collect()
+    });
+
+    macro_rules! read_op {
+        ($stmt:stmt) => {
+            {
+               $stmt
+               pc += 1;
+            }
+        }
+    }
+
+    for (op, count) in op_groups {
+        match op {
+            Op::LoopStart => read_op!({
+                compressed_ops.push(CompressedOp::LoopStart);
+                map_stack.push(pc);
+            }),
+            Op::LoopEnd => read_op!({
+                compressed_ops.push(CompressedOp::LoopEnd);
+                let begin = map_stack.pop().expect("Unmatched loop end");
+                jump_table[begin] = pc + 1;
+                jump_table[pc] = begin + 1;
+            }),
+            _ => read_op!(compressed_ops.push(compress_group::<C>(op, count))),
+        }
+    }
+
+    Code { ops: compressed_ops, jump_table, source_map }
+}
+
+/// Compressed operation for a group of `count` repeated operations
+fn compress_group<C: Cell>(op: Op, count: usize) -> CompressedOp {
+    match op {
+        Op::Inc => CompressedOp::Add(C::wrap_usize(count).to_u32()),
+        Op::Dec => CompressedOp::Sub(C::wrap_usize(count).to_u32()),
+        Op::IncPtr => CompressedOp::Forward(count),
+        Op::DecPtr => CompressedOp::Back(count),
+        Op::PutChar => CompressedOp::PutChar,
+        Op::GetChar => CompressedOp::GetChar,
+        Op::LoopStart => CompressedOp::LoopStart,
+        Op::LoopEnd => CompressedOp::LoopEnd,
+        Op::Assert => CompressedOp::Assert,
+        Op::SetZero => CompressedOp::SetZero,
+        Op::PutPointer => CompressedOp::PutPointer,
+        Op::Nop => unreachable!("Compressing drops no-ops"),
+    }
+}
+
+/// Represents runnable operations
+pub trait Runnable {
+    /// Run the operation over code and environment
+    fn run<R: Read, W: Write, T: ?Sized + Tape>(&self, code: &Code<Self>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> where Self: Sized;
+
+    fn process_loop_start<R: Read, W: Write, T: ?Sized + Tape>(code: &Code<Self>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> where Self: Sized {
+        if env.read_condition_checked()? == T::Cell::ZERO {
+            env.set_pc(code.jump_table[env.pc]);
+        } else {
+            env.advance_pc();
+        };
+        Ok(())
+    }
+
+    fn process_loop_end<R: Read, W: Write, T: ?Sized + Tape>(code: &Code<Self>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> where Self: Sized {
+        if env.read_condition_checked()? != T::Cell::ZERO {
+            if env.options.forward_only {
+                return Err(RuntimeError::BackwardJumpDisallowed { pc: env.pc, source_offset: None });
+            }
+            env.set_pc(code.jump_table[env.pc]);
+        } else {
+            env.advance_pc();
+        }
+        Ok(())
+    }
+}
+
+impl Runnable for Op {
+    fn run<R: Read, W: Write, T: ?Sized + Tape>(&self, code: &Code<Self>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> {
+        match self {
+            Op::Inc => { env.add(T::Cell::wrap_u32(1))?; env.advance_pc(); }
+            Op::Dec => { env.sub(T::Cell::wrap_u32(1))?; env.advance_pc(); }
+            Op::IncPtr => { env.add_ptr(1)?; env.advance_pc(); }
+            Op::DecPtr => { env.sub_ptr(1)?; env.advance_pc(); }
+            Op::PutChar => { env.put_char()?; env.advance_pc(); }
+            Op::GetChar => { env.read_char()?; env.advance_pc(); }
+            Op::Assert => { env.assert_nonzero()?; env.advance_pc(); }
+            Op::SetZero => { env.set_data(T::Cell::ZERO)?; env.advance_pc(); }
+            Op::PutPointer => { env.put_pointer()?; env.advance_pc(); }
+            Op::Nop => env.advance_pc(),
+            Op::LoopStart => {
+                Runnable::process_loop_start(code, env)?;
+            }
+            Op::LoopEnd => {
+                Runnable::process_loop_end(code, env)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Runnable for CompressedOp {
+    fn run<R: Read, W: Write, T: ?Sized + Tape>(&self, code: &Code<Self>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> where Self: Sized {
+        match self {
+            CompressedOp::Add(n) => { env.add(T::Cell::wrap_u32(*n))?; env.advance_pc(); }
+            CompressedOp::Sub(n) => { env.sub(T::Cell::wrap_u32(*n))?; env.advance_pc(); }
+            CompressedOp::Back(n) => { env.sub_ptr(*n)?; env.advance_pc(); }
+            CompressedOp::Forward(n) => { env.add_ptr(*n)?; env.advance_pc(); }
+            CompressedOp::PutChar => { env.put_char()?; env.advance_pc(); }
+            CompressedOp::GetChar => { env.read_char()?; env.advance_pc(); }
+            CompressedOp::SetZero => { env.set_data(T::Cell::ZERO)?; env.advance_pc(); }
+            CompressedOp::SetValue(n) => { env.set_data(T::Cell::wrap_u32(*n))?; env.advance_pc(); }
+            CompressedOp::Assert => { env.assert_nonzero()?; env.advance_pc(); }
+            CompressedOp::PutPointer => { env.put_pointer()?; env.advance_pc(); }
+            CompressedOp::EndIf => env.advance_pc(),
+            CompressedOp::LoopStart | CompressedOp::IfNonzero => {
+                Runnable::process_loop_start(code, env)?;
+            }
+            CompressedOp::LoopEnd => {
+                Runnable::process_loop_end(code, env)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Execute operations
+pub fn run<R: Read, W: Write, T: ?Sized + Tape, O: Runnable>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> {
+    run_with_hook(code, env, |_, _| ControlFlow::Continue(()))
+}
+
+/// Validate code, then execute operations.
+/// Malformed code such as decoded from untrusted bytecode fails without running instead of panicking.
+pub fn run_checked<R, W, T, O>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<(), CheckedRunError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable + Bracket
+{
+    code.validate().map_err(CheckedRunError::Invalid)?;
+    run(code, env).map_err(CheckedRunError::Runtime)
+}
+
+/// Execute operations, calling the hook before each one.
+/// Stops without an error when the hook breaks.
+pub fn run_with_hook<R, W, T, O, F>(code: &Code<O>, env: &mut Environment<R, W, T>, mut hook: F) -> Result<(), RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable, F: FnMut(&O, &Environment<R, W, T>) -> ControlFlow<()>
+{
+    let len_ops = code.ops.len();
+
+    while len_ops > env.pc {
+        if hook(&code.ops[env.pc], env).is_break() {
+            break;
+        }
+        step(code, env)?;
+    }
+    Ok(())
+}
+
+/// Execute the operation at the program counter.
+/// Returns false without running anything when the program has ended.
+pub fn step<R: Read, W: Write, T: ?Sized + Tape, O: Runnable>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<bool, RuntimeError> {
+    let pc = env.pc;
+    match code.ops.get(pc) {
+        Some(op) => {
+            env.count_step()
+                .and_then(|_| op.run(code, env))
+                .map_err(|err| err.with_source_offset(code.source_offset(pc)))?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Cells a run changed with their new data, ordered by index into the tape after running
+pub type TapeDiff<C = u8> = Vec<(usize, C)>;
+
+/// Execute operations, returning the cells whose data differs from before running.
+/// Cells the tape grew by count as having been zero.
+pub fn run_with_diff<R, W, T, O>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<TapeDiff<T::Cell>, RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable
+{
+    let initial = (0..env.data.len()).map(|index| env.data.get(index)).collect::<Vec<_>>();
+    let grown_before = env.grown_front;
+
+    run(code, env)?;
+
+    let shift = env.grown_front - grown_before;
+    Ok((0..env.data.len())
+        .filter_map(|index| {
+            let old = index.checked_sub(shift).and_then(|index| initial.get(index)).copied().unwrap_or(T::Cell::ZERO);
+            let new = env.data.get(index);
+            if new != old { Some((index, new)) } else { None }
+        })
+        .collect())
+}
+
+/// Execute operations, calling the callback with the data under the pointer
+/// truncated to a byte and the program counter each time an operation puts a
+/// character. Other output, such as the pointer's position, isn't traced.
+pub fn run_with_output_trace<R, W, T, O, F>(code: &Code<O>, env: &mut Environment<R, W, T>, mut callback: F) -> Result<(), RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable, F: FnMut(u8, usize)
+{
+    loop {
+        let pc = env.pc;
+        let chars_put = env.chars_put;
+        if !step(code, env)? {
+            return Ok(());
+        }
+        if env.chars_put > chars_put {
+            callback(env.read_data().to_u32() as u8, pc);
+        }
+    }
+}
+
+/// Execute operations, calling the callback with the index at the time, old data
+/// and new data of a cell each time an operation writes it, even with the same data.
+pub fn run_with_memwatch<R, W, T, O, F>(code: &Code<O>, env: &mut Environment<R, W, T>, mut callback: F) -> Result<(), RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable, F: FnMut(usize, T::Cell, T::Cell)
+{
+    env.write_log = Some(Vec::new());
+    let result = loop {
+        let running = step(code, env);
+        for (index, old, new) in env.write_log.iter_mut().flat_map(|log| log.drain(..)) {
+            callback(index, old, new);
+        }
+        match running {
+            Ok(true) => {}
+            Ok(false) => break Ok(()),
+            Err(err) => break Err(err),
+        }
+    };
+    env.write_log = None;
+    result
+}
+
+/// Execute codes one after another over the same environment.
+/// Only the program counter is reset between them, so tape and pointer carry over.
+pub fn run_sequence<R: Read, W: Write, T: ?Sized + Tape, O: Runnable>(codes: &[Code<O>], env: &mut Environment<R, W, T>) -> Result<(), RuntimeError> {
+    for code in codes {
+        env.set_pc(0);
+        run(code, env)?;
+    }
+    Ok(())
+}
+
+/// Parse, compress and run source code with the options over a fresh tape.
+/// The writer is flushed even when running fails, so no output is left buffered.
+pub fn execute<R: Read, W: Write>(source: &str, language: &Language, options: &RunOptions, reader: &mut R, writer: &mut W) -> Result<(), RuntimeError> {
+    let code = compress(&parse_with_source_map(source, language));
+    let mut data = vec![0u8; options.tape_size];
+    let mut env = Environment::with_options(&mut data, reader, writer, options.clone());
+
+    let result = run(&code, &mut env);
+    let pc = env.pc;
+    let finished = env.finish().map_err(|err| RuntimeError::Io { kind: err.kind(), pc, source_offset: None });
+    result.and(finished)
+}
+
+/// Number of operations the source code compresses to over the number it parses
+/// to, or 1 for code without any operations
+pub fn compression_ratio(source: &str, language: &Language) -> f64 {
+    let ops = parse(source, language);
+    if ops.ops.is_empty() {
+        return 1.0;
+    }
+    compress(&ops).ops.len() as f64 / ops.ops.len() as f64
+}
+
+/// Outcome of grading a program.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct GradeResult {
+    /// Whether the program ran to its end without an error
+    pub terminated: bool,
+    /// Number of instructions run
+    pub steps: u64,
+    /// Everything written before the program ended or failed
+    pub output: Vec<u8>,
+    /// Error which stopped the program, such as leaving the tape or running out of steps
+    pub violation: Option<RuntimeError>,
+}
+
+/// Run source code uncompressed on the input over a fresh tape of `tape_size`
+/// cells, failing if the pointer leaves the tape or more than `max_steps`
+/// instructions run
+pub fn grade(source: &str, language: &Language, input: &[u8], tape_size: usize, max_steps: u64) -> GradeResult {
+    let code = parse_with_source_map(source, language);
+    let options = RunOptions::builder()
+        .pointer_policy(PointerPolicy::Error)
+        .step_limit(Some(max_steps))
+        .build();
+
+    let mut data = vec![0u8; tape_size];
+    let mut reader = input;
+    let mut output = Vec::new();
+    let mut env = Environment::with_options(&mut data, &mut reader, &mut output, options);
+    let violation = run(&code, &mut env).err();
+    let steps = env.steps();
+
+    GradeResult { terminated: violation.is_none(), steps, output, violation }
+}
+
+/// Whether both programs write the same output and leave the same tape for each
+/// of the inputs, running on fresh tapes of the default size. Programs failing or
+/// running more than `max_steps` operations on any input aren't equivalent.
+pub fn programs_equivalent(a: &str, b: &str, language: &Language, inputs: &[&[u8]], max_steps: u64) -> bool {
+    let options = RunOptions::builder().step_limit(Some(max_steps)).build();
+    let codes = [compress(&parse(a, language)), compress(&parse(b, language))];
+
+    let run_on = |code: &Code<CompressedOp>, input: &[u8]| -> Option<(Vec<u8>, Vec<u8>)> {
+        let mut data = vec![0u8; options.tape_size];
+        let mut reader = input;
+        let mut output = Vec::new();
+        let mut env = Environment::with_options(&mut data, &mut reader, &mut output, options.clone());
+        run(code, &mut env).ok()?;
+        Some((output, data))
+    };
+
+    inputs.iter().all(|input| {
+        match (run_on(&codes[0], input), run_on(&codes[1], input)) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    })
+}
+
+/// Run source code in the default language and options with the input,
+/// returning the output with invalid UTF-8 replaced
+pub fn execute_str(source: &str, input: &str) -> Result<String, RuntimeError> {
+    let mut reader = input.as_bytes();
+    let mut output = Vec::new();
+    execute(source, &Language::default(), &RunOptions::default(), &mut reader, &mut output)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+/// Program printing "Hello World!\n", shared by tests across modules
+#[cfg(test)]
+pub(crate) const HELLO_BF: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::from_utf8;
+    use std::io::Cursor;
+
+    const BUF_SIZE: usize = 1024;
+
+    #[test]
+    fn test_parse_ops() {
+        let source = "+-.,[><]".to_string();
+        let language = Language::default();
+
+        let result = parse(&source, &language);
+
+        assert_eq!(result.ops, vec![
+            Op::Inc,
+            Op::Dec,
+            Op::PutChar,
+            Op::GetChar,
+            Op::LoopStart,
+            Op::IncPtr,
+            Op::DecPtr,
+            Op::LoopEnd,
+        ]);
+    }
+
+    #[test]
+    fn test_parse_jumps() {
+        let source = "[+++]--[+[+]+]".to_string();
+        let language = Language::default();
+
+        let result = parse(&source, &language);
+
+        assert_eq!(result.jump_table[0], 5);
+        assert_eq!(result.jump_table[4], 1);
+
+        assert_eq!(result.jump_table[7], 14);
+        assert_eq!(result.jump_table[13], 8);
+
+        assert_eq!(result.jump_table[9], 12);
+        assert_eq!(result.jump_table[11], 10);
+    }
+
+    #[test]
+    fn test_parse_with_limits() {
+        let language = Language::default();
+        let source = "+[>[-[<]]]>[+]";
+
+        let code = parse_with_limits(source, &language, 3).unwrap();
+        assert_eq!(code.ops, parse(source, &language).ops);
+        assert_eq!(code.jump_table, parse(source, &language).jump_table);
+
+        assert_eq!(parse_with_limits(source, &language, 2).err(), Some(ParseError::NestingTooDeep { depth: 3, limit: 2 }));
+        assert!(parse_with_limits("+-<>", &language, 0).is_ok());
+        assert!(parse_with_limits("[]", &language, 0).is_err());
+
+        assert_eq!(parse_with_limits("[]]+[", &language, 3), Err(ParseError::UnmatchedLoopEnd { offset: 2 }));
+        assert_eq!(parse_with_limits("[[+[]", &language, 3), Err(ParseError::UnmatchedLoopStart { offset: 0 }));
+    }
+
+    #[test]
+    fn test_check_brackets() {
+        let language = Language::default();
+
+        assert_eq!(check_brackets("+[->[+]<]x[]", &language), Ok(()));
+        assert_eq!(check_brackets("no brackets", &language), Ok(()));
+        assert_eq!(check_brackets("[]]+[", &language), Err(ParseError::UnmatchedLoopEnd { offset: 2 }));
+        assert_eq!(check_brackets("[][[]", &language), Err(ParseError::UnmatchedLoopStart { offset: 2 }));
+        assert_eq!(check_brackets("[[+[]", &language), Err(ParseError::UnmatchedLoopStart { offset: 0 }));
+        assert_eq!(check_brackets("é[", &language), Err(ParseError::UnmatchedLoopStart { offset: 2 }));
+    }
+
+    #[test]
+    fn test_code_clone() {
+        let code = parse_with_source_map("+[->+<]", &Language::default());
+        let cloned = code.clone();
+        assert_eq!(cloned, code);
+        assert_eq!(cloned.jump_table, [0, 7, 0, 0, 0, 0, 2]);
+        assert_ne!(cloned, parse("+[->+<]", &Language::default()));
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let language = Language::default();
+
+        let code = parse("+[->+<]", &language);
+        assert_eq!(code.fingerprint(), parse_with_source_map("+[->+<]", &language).fingerprint());
+        assert_eq!(code.fingerprint(), parse("+ [-> +<] comment", &language).fingerprint());
+        assert_ne!(code.fingerprint(), parse("+[-<+>]", &language).fingerprint());
+        assert_ne!(compress(&parse("++", &language)).fingerprint(), compress(&parse("+++", &language)).fingerprint());
+
+        // Pinned, so caches and checkpoints keyed by it stay valid
+        assert_eq!(parse("+-", &language).fingerprint(), 0xb026_cb45_7020_ada6);
+        let language = language.with_put_pointer('$').unwrap();
+        assert_eq!(compress(&parse("++>>[-]<$.,", &language)).fingerprint(), 0xf15e_7b6a_bc9d_c031);
+        let code = Code {
+            ops: vec![CompressedOp::SetValue(7), CompressedOp::IfNonzero, CompressedOp::EndIf, CompressedOp::Assert, CompressedOp::Sub(3)],
+            jump_table: vec![0; 5],
+            source_map: None,
+        };
+        assert_eq!(code.fingerprint(), 0x1885_bb66_12f1_1f11);
+    }
+
+    #[test]
+    fn test_parse_empty() {
+        let language = Language::default();
+
+        let result = parse("only comments here", &language);
+        assert!(result.is_empty());
+        assert_eq!(result.jump_table.capacity(), 0);
+
+        let mut data = [0; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&result, &mut env).unwrap();
+        assert_eq!(env.pc(), 0);
+
+        assert!(!parse("+", &language).is_empty());
+    }
+
+    #[test]
+    fn test_run() {
+        // hello.bf
+        let language = Language::default();
+
+        let ops = parse(HELLO_BF, &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        run(&ops, &mut env).unwrap();
+
+        let output_string = from_utf8(&output[0..13]).expect("Encoding error");
+        assert_eq!(output_string, "Hello World!\n");
+    }
+
+    #[test]
+    fn test_run_sequence() {
+        let language = Language::default();
+
+        let codes = [
+            compress(&parse(">+++++", &language)),
+            compress(&parse("[-<++++++++++>]", &language)),
+            compress(&parse("<+++.", &language)),
+        ];
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run_sequence(&codes, &mut env).unwrap();
+        assert_eq!(env.pointer(), 0);
+        assert_eq!(env.pc(), codes[2].ops.len());
+        assert_eq!(output, b"5");
+    }
+
+    #[test]
+    fn test_put_pointer() {
+        let language = Language::default().with_put_pointer('$').unwrap();
+        assert!(Language::default().with_put_pointer('>').is_none());
+        assert!(!Language::default().is_token('$'));
+
+        let ops = parse(">>>$<$", &language);
+        assert_eq!(ops.ops[3], Op::PutPointer);
+
+        let mut data = [0; 4];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&ops, &mut env).unwrap();
+        assert_eq!(output, b"@3 @2 ");
+
+        let code = compress(&ops);
+        assert_eq!(code.ops, [CompressedOp::Forward(3), CompressedOp::PutPointer, CompressedOp::Back(1), CompressedOp::PutPointer]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(output, b"@3 @2 ");
+        assert_eq!(code.to_rle_source(&language).unwrap(), "3>$<$");
+
+        // Positions stay put as the tape grows in front
+        let mut tape = BiTape::<u8>::new(1);
+        let mut output = Vec::new();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run(&parse("$<<$>$", &language), &mut env).unwrap();
+        assert_eq!(output, b"@0 @-2 @-1 ");
+    }
+
+    #[test]
+    fn test_run_with_diff() {
+        let language = Language::default();
+
+        let mut data = [0, 0, 7];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run_with_diff(&compress(&parse("+++++", &language)), &mut env), Ok(vec![(0, 5)]));
+
+        // Cells written back to what they were don't count
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run_with_diff(&compress(&parse("+->+>[-]", &language)), &mut env), Ok(vec![(1, 1), (2, 0)]));
+
+        let mut tape = BiTape::<u8>::new(1);
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        assert_eq!(run_with_diff(&parse("+<<++>", &language), &mut env), Ok(vec![(0, 2), (2, 1)]));
+    }
+
+    #[test]
+    fn test_run_with_output_trace() {
+        let language = Language::default();
+        let code = compress(&parse(HELLO_BF, &language));
+
+        let mut data = [0; 16];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        let mut trace = Vec::new();
+        run_with_output_trace(&code, &mut env, |byte, pc| trace.push((byte, pc))).unwrap();
+        assert_eq!(trace.len(), 13);
+        assert!(trace.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert!(trace.iter().all(|&(_, pc)| code.ops[pc] == CompressedOp::PutChar));
+        assert_eq!(trace.iter().map(|&(byte, _)| byte).collect::<Vec<_>>(), output);
+
+        let language = language.with_put_pointer('$').unwrap();
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let mut trace = Vec::new();
+        run_with_output_trace(&parse("[-]+.$.", &language), &mut env, |byte, pc| trace.push((byte, pc))).unwrap();
+        assert_eq!(trace, [(1, 4), (1, 6)]);
+        assert_eq!(output, b"\x01@0 \x01");
+    }
+
+    #[test]
+    fn test_run_with_memwatch() {
+        let language = Language::default();
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![7]);
+        let mut output = Vec::new();
+
+        let mut writes = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run_with_memwatch(&parse("+++", &language), &mut env, |index, old, new| writes.push((index, old, new))).unwrap();
+        assert_eq!(writes, [(0, 0, 1), (0, 1, 2), (0, 2, 3)]);
+
+        // Moves and output aren't writes, but writes leaving data as it was, like reading zero at EOF, are
+        let mut writes = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run_with_memwatch(&fold_clear_loops(&compress(&parse(">,.<[-]>[-],", &language))), &mut env, |index, old, new| writes.push((index, old, new))).unwrap();
+        assert_eq!(writes, [(1, 0, 7), (0, 3, 0), (1, 7, 0), (1, 0, 0)]);
+
+        // Growing the tape in front shifts cells without writing them
+        let mut writes = Vec::new();
+        let mut tape = BiTape::<u8>::new(1);
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run_with_memwatch(&parse("+<++", &language), &mut env, |index, old, new| writes.push((index, old, new))).unwrap();
+        assert_eq!(writes, [(0, 0, 1), (0, 0, 1), (0, 1, 2)]);
+    }
+
+    #[test]
+    fn test_forward_only() {
+        let language = Language::default();
+        let options = RunOptions::builder().forward_only(true).build();
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert_eq!(run(&parse_with_source_map("++[-]", &language), &mut env),
+                   Err(RuntimeError::BackwardJumpDisallowed { pc: 4, source_offset: Some(4) }));
+
+        // Loops whose body runs once never jump back
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, RunOptions::default());
+        env.set_forward_only(true);
+        run(&compress(&parse("[-]+[-]>+++.", &language)), &mut env).unwrap();
+        assert_eq!(output, [3]);
+    }
+
+    #[test]
+    fn test_condition_cell() {
+        let language = Language::default();
+        let code = parse(">[<->+]", &language);
+
+        // Each time round the loop the pointer starts on the second cell, whose data
+        // the loop would test by default
+        let mut data = [3, 0];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let options = RunOptions::builder().condition_cell(Some(0)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        run(&code, &mut env).unwrap();
+        assert_eq!(data, [0, 3]);
+
+        let mut data = [3, 0];
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(data, [3, 0]);
+
+        // The condition cell stays the same cell as the tape grows in front
+        let mut tape = BiTape::<u8>::new(1);
+        let options = RunOptions::builder().condition_cell(Some(0)).build();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, options);
+        run(&parse("+<[>-<]", &language), &mut env).unwrap();
+        assert_eq!((env.cell(0), env.cell(1)), (Some(0), Some(0)));
+    }
+
+    #[test]
+    fn test_rewind() {
+        let code = compress(&parse(",[>+<-]>.", &Language::default()));
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![3, 4]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        env.rewind();
+        assert_eq!((env.pc(), env.pointer()), (0, 0));
+        run(&code, &mut env).unwrap();
+        assert_eq!(output, [3, 7]);
+        assert_eq!(data, [0, 7]);
+
+        // Back to where the pointer started, wherever the tape grew since
+        let code = parse("<<+", &Language::default());
+        let mut tape = BiTape::<u8>::new(3);
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        env.set_pointer(1).unwrap();
+        run(&code, &mut env).unwrap();
+        env.rewind();
+        assert_eq!(env.pointer(), 2);
+        run(&code, &mut env).unwrap();
+        env.rewind();
+        assert_eq!(env.pointer(), 2);
+        assert_eq!((env.cell(0), env.cell(1), env.cell(2)), (Some(2), Some(0), Some(0)));
+    }
+
+    #[test]
+    fn test_with_pointer() {
+        let ops = parse("+>++", &Language::default());
+
+        let mut data = [0; 5];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_pointer(&mut data, &mut input, &mut output, 3).unwrap();
+        assert_eq!(env.pointer(), 3);
+        run(&ops, &mut env).unwrap();
+        assert_eq!(env.pointer(), 4);
+        assert_eq!(env.set_pointer(5), Err(RuntimeError::PointerOverflow { index: 5, tape_len: 5, pc: 4, source_offset: None }));
+        assert_eq!(data, [0, 0, 0, 1, 2]);
+
+        let mut data = [0; 5];
+        assert!(Environment::with_pointer(&mut data, &mut input, &mut output, 5).is_err());
+    }
+
+    #[test]
+    fn test_run_safe() {
+        let language = Language::default();
+
+        let ops = parse("<<<<<<.>>>>>>.", &language);
+
+        let mut data = [0; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        // Should not panic
+        run(&ops, &mut env).unwrap();
+    }
+
+    #[test]
+    fn test_run_pointer_error() {
+        let language = Language::default();
+
+        let ops = parse_with_source_map("+> <\n <", &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_pointer_policy(PointerPolicy::Error);
+
+        assert_eq!(run(&ops, &mut env), Err(RuntimeError::PointerUnderflow { index: -1, pc: 3, source_offset: Some(6) }));
+    }
+
+    #[test]
+    fn test_run_pointer_error_compressed() {
+        let language = Language::default();
+
+        let ops = parse_with_source_map("+ [->+<] >>>", &language);
+        let compressed_ops = compress(&ops);
+
+        let mut data = [0; 3];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_pointer_policy(PointerPolicy::Error);
+
+        // The error points at the start of the `>>>` run
+        assert_eq!(run(&compressed_ops, &mut env), Err(RuntimeError::PointerOverflow { index: 3, tape_len: 3, pc: 7, source_offset: Some(9) }));
+    }
+
+    #[test]
+    fn test_pointer_error_payloads() {
+        let language = Language::default();
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+
+        let mut data = [0; 4];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+        assert_eq!(run(&parse("<", &language), &mut env), Err(RuntimeError::PointerUnderflow { index: -1, pc: 0, source_offset: None }));
+
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+        assert_eq!(run(&compress(&parse(">>>>>>>>>>", &language)), &mut env),
+                   Err(RuntimeError::PointerOverflow { index: 10, tape_len: 4, pc: 0, source_offset: None }));
+
+        // Indices saturate instead of wrapping
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        env.set_pointer(1).unwrap();
+        assert_eq!(env.add_ptr(usize::MAX), Err(RuntimeError::PointerOverflow { index: usize::MAX, tape_len: 4, pc: 0, source_offset: None }));
+        assert_eq!(env.sub_ptr(usize::MAX), Err(RuntimeError::PointerUnderflow { index: isize::MIN, pc: 0, source_offset: None }));
+    }
+
+    #[test]
+    fn test_parse_source_map() {
+        let language = Language::default();
+
+        assert_eq!(parse("+ -", &language).source_map(), None);
+        assert_eq!(parse_with_source_map("+ -", &language).source_map(), Some(&[0, 2][..]));
+
+        let compressed_ops = compress(&parse_with_source_map("++ --.", &language));
+        assert_eq!(compressed_ops.source_map(), Some(&[0, 3, 5][..]));
+    }
+
+    #[test]
+    fn test_validate() {
+        let language = Language::default();
+
+        assert_eq!(parse("+[[-]>]", &language).validate(), Ok(()));
+        assert_eq!(compress(&parse("+[[-]>]", &language)).validate(), Ok(()));
+
+        let mut code = parse("+[[-]>]", &language);
+        code.jump_table[2] = 6;
+        assert_eq!(code.validate(), Err(ValidationError::BadJump { pc: 2 }));
+
+        let code = Code { ops: vec![Op::LoopStart], jump_table: vec![0], source_map: None };
+        assert_eq!(code.validate(), Err(ValidationError::UnmatchedLoopStart { pc: 0 }));
+
+        let code = Code { ops: vec![Op::LoopEnd], jump_table: vec![0], source_map: None };
+        assert_eq!(code.validate(), Err(ValidationError::UnmatchedLoopEnd { pc: 0 }));
+
+        let code = Code { ops: vec![Op::Inc], jump_table: vec![], source_map: None };
+        assert_eq!(code.validate(), Err(ValidationError::JumpTableLength { ops: 1, jump_table: 0 }));
+    }
+
+    #[test]
+    fn test_run_checked() {
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut code = compress(&parse("+[>+<-]>.", &Language::default()));
+        code.jump_table[1] = 100;
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run_checked(&code, &mut env), Err(CheckedRunError::Invalid(ValidationError::BadJump { pc: 1 })));
+        assert_eq!(env.pc(), 0);
+
+        let code = Code { ops: vec![CompressedOp::LoopEnd], jump_table: vec![], source_map: None };
+        assert_eq!(run_checked(&code, &mut env),
+                   Err(CheckedRunError::Invalid(ValidationError::JumpTableLength { ops: 1, jump_table: 0 })));
+
+        let code = compress(&parse("+[>+<-]>.<<", &Language::default()));
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert_eq!(run_checked(&code, &mut env),
+                   Err(CheckedRunError::Runtime(RuntimeError::PointerUnderflow { index: -1, pc: 9, source_offset: None })));
+        assert_eq!(output, [1]);
+    }
+
+    #[test]
+    fn test_concat() {
+        let language = Language::default();
+        let first = "++++++[>++++++++<-]>+.";
+        let second = "[-]+++++[>+++++++++++++<-]>.";
+
+        let code = parse(first, &language).concat(&parse(second, &language));
+        assert_eq!(code, parse(&format!("{}{}", first, second), &language));
+        assert!(code.validate().is_ok());
+        let compressed = compress(&parse(first, &language)).concat(&compress(&parse(second, &language)));
+        assert!(compressed.validate().is_ok());
+
+        let mut data = [0; 3];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&compressed, &mut env).unwrap();
+        assert_eq!(output, b"1A");
+
+        let code = parse_with_source_map("+ [-]", &language).concat(&parse_with_source_map(" .", &language));
+        assert_eq!(code.source_map(), Some(&[0, 2, 3, 4, 6][..]));
+        assert_eq!(parse("+", &language).concat(&parse_with_source_map(".", &language)).source_map(), None);
+    }
+
+    #[test]
+    fn test_compress() {
+        let source = "+++++[>>>----<<<[[..]],,]".to_string();
+        let language = Language::default();
+
+        let ops = parse(&source, &language);
+        let compressed_ops = compress(&ops);
+
+        assert_eq!(compressed_ops.ops, [
+            CompressedOp::Add(5),
+            CompressedOp::LoopStart,
+            CompressedOp::Forward(3),
+            CompressedOp::Sub(4),
+            CompressedOp::Back(3),
+            CompressedOp::LoopStart,
+            CompressedOp::LoopStart,
+            CompressedOp::PutChar,
+            CompressedOp::PutChar,
+            CompressedOp::LoopEnd,
+            CompressedOp::LoopEnd,
+            CompressedOp::GetChar,
+            CompressedOp::GetChar,
+            CompressedOp::LoopEnd,
+        ]);
+
+        assert_eq!(compressed_ops.jump_table[1], 14);
+        assert_eq!(compressed_ops.jump_table[13], 2);
+
+        assert_eq!(compressed_ops.jump_table[5], 11);
+        assert_eq!(compressed_ops.jump_table[10], 6);
+
+        assert_eq!(compressed_ops.jump_table[6], 10);
+        assert_eq!(compressed_ops.jump_table[9], 7);
+    }
+
+    #[test]
+    fn test_compress_ungrouped() {
+        let language = Language::default();
+
+        let ops = parse_with_source_map("+>-<[.,]", &language);
+        let compressed = compress(&ops);
+        assert_eq!(compressed.ops.len(), ops.ops.len());
+        assert_eq!(compressed.jump_table, ops.jump_table);
+        assert_eq!(compressed.source_map, ops.source_map);
+        assert!(compressed.validate().is_ok());
+
+        // Hello World has runs, but its output is the same either way
+        let ops = parse(HELLO_BF, &language);
+        assert!(compress(&ops).ops.len() < ops.ops.len());
+        let (plain, compressed) = run_both(HELLO_BF, b"").expect("Hello World didn't halt");
+        assert_eq!(&plain.0[..13], b"Hello World!\n");
+        assert_eq!(plain, compressed);
+    }
+
+    #[test]
+    fn test_compress_with() {
+        let language = Language::default();
+        let ops = parse_with_source_map("++>>>--<", &language);
+
+        let config = CompressConfig { merge_ptr: false, ..CompressConfig::default() };
+        let compressed = compress_with::<u8>(&ops, &config);
+        assert_eq!(compressed.ops, [
+            CompressedOp::Add(2),
+            CompressedOp::Forward(1),
+            CompressedOp::Forward(1),
+            CompressedOp::Forward(1),
+            CompressedOp::Sub(2),
+            CompressedOp::Back(1),
+        ]);
+        assert_eq!(compressed.source_map(), Some(&[0, 2, 3, 4, 5, 7][..]));
+
+        let config = CompressConfig { merge_data: false, ..CompressConfig::default() };
+        let compressed = compress_with::<u8>(&ops, &config);
+        assert_eq!(compressed.ops, [
+            CompressedOp::Add(1),
+            CompressedOp::Add(1),
+            CompressedOp::Forward(3),
+            CompressedOp::Sub(1),
+            CompressedOp::Sub(1),
+            CompressedOp::Back(1),
+        ]);
+        assert_eq!(compress_with::<u8>(&ops, &CompressConfig::default()), compress(&ops));
+    }
+
+    #[test]
+    fn test_parse_compressed() {
+        let language = Language::default();
+        let many_incs = "+".repeat(300);
+        for source in ["", HELLO_BF, "+-<>.,[]", "[[-]>+<]>>>.<<<", "a ++ b -- c {+} [,.]", many_incs.as_str()] {
+            assert_eq!(parse_compressed(source, &language), Ok(compress(&parse(source, &language))));
+        }
+
+        let language = Language::default().with_nop('_').unwrap();
+        assert_eq!(parse_compressed("+_+[_]", &language), Ok(compress(&parse("+_+[_]", &language))));
+
+        let language = Language::default();
+        assert_eq!(parse_compressed("[]]+[", &language), Err(ParseError::UnmatchedLoopEnd { offset: 2 }));
+        assert_eq!(parse_compressed("[[+[]", &language), Err(ParseError::UnmatchedLoopStart { offset: 0 }));
+    }
+
+    #[test]
+    fn test_compress_cell_width() {
+        let language = Language::default();
+
+        let ops = parse(&"+".repeat(300), &language);
+
+        // Wraps around u8 cells
+        let compressed_ops = compress(&ops);
+        assert_eq!(compressed_ops.ops, [CompressedOp::Add(44)]);
+
+        let mut data = [0u8; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&compressed_ops, &mut env).unwrap();
+        assert_eq!(env.read_data(), 44);
+
+        // Doesn't wrap around u16 cells
+        let compressed_ops = compress_for::<u16>(&ops);
+        assert_eq!(compressed_ops.ops, [CompressedOp::Add(300)]);
+
+        let mut data = [0u16; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::with_cells(&mut data, &mut input, &mut output, RunOptions::default());
+        run(&compressed_ops, &mut env).unwrap();
+        assert_eq!(env.read_data(), 300);
+
+        // Same as running uncompressed
+        let mut data = [0u16; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::with_cells(&mut data, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(env.read_data(), 300);
+    }
+
+    #[test]
+    fn test_format_error() {
+        let source = "+>\n\t <<-";
+        let ops = parse_with_source_map(source, &Language::default());
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        let err = run(&ops, &mut env).unwrap_err();
+
+        let expected = "error: pointer underflow to -1 at line 2, column 4 ('<')\n  |\n2 | \t <<-\n  | \t  ^";
+        assert_eq!(format_error(&err, source, ops.source_map().unwrap()), expected);
+
+        // Falls back to the source map without an offset in the error
+        let err = RuntimeError::PointerUnderflow { index: -1, pc: 3, source_offset: None };
+        assert_eq!(format_error(&err, source, ops.source_map().unwrap()), expected);
+        assert_eq!(format_error(&err, source, &[]), "error: pointer underflow to -1 at pc 3");
+    }
+
+    #[test]
+    fn test_wide_char_policy() {
+        let ops = parse(".", &Language::default());
+
+        let put = |value: u32, policy: WideCharPolicy| {
+            let mut data = [value];
+            let mut input = Cursor::new(vec![]);
+            let mut output = Vec::new();
+            let options = RunOptions::builder().wide_char_policy(policy).build();
+            let mut env = Environment::with_cells(&mut data, &mut input, &mut output, options);
+            run(&ops, &mut env).map(|()| String::from_utf8(output).unwrap())
+        };
+
+        assert_eq!(put(0x1F600, WideCharPolicy::Truncate), Ok("\0".to_owned()));
+        assert_eq!(put(0x1F600, WideCharPolicy::Error), Err(RuntimeError::NonByteOutput { value: 0x1F600, pc: 0, source_offset: None }));
+        assert_eq!(put(0x1F600, WideCharPolicy::Utf8), Ok("😀".to_owned()));
+        assert_eq!(put(0xD800, WideCharPolicy::Utf8), Err(RuntimeError::NonByteOutput { value: 0xD800, pc: 0, source_offset: None }));
+
+        // Data fitting in a byte is put the same under every policy
+        for policy in [WideCharPolicy::Truncate, WideCharPolicy::Error, WideCharPolicy::Utf8] {
+            assert_eq!(put(0xE9, policy), Ok("é".to_owned()));
+        }
+    }
+
+    #[test]
+    fn test_rle_source() {
+        let language = Language::default();
+
+        let source = format!("{}[>{}<-]>{}.{}.", "+".repeat(40), "+".repeat(100), "-".repeat(35), ">".repeat(12));
+        let code = compress(&parse(&source, &language));
+
+        let rle = code.to_rle_source(&language).unwrap();
+        assert_eq!(rle, "40+[>100+<-]>35-.12>.");
+
+        let round_trip = compress(&parse(&expand_rle(&rle, &language).unwrap(), &language));
+        assert_eq!(round_trip.ops, code.ops);
+        assert_eq!(round_trip.jump_table, code.jump_table);
+    }
+
+    #[test]
+    fn test_split_program_input() {
+        let (program, input) = split_program_input(",.!AB");
+        assert_eq!((program, input), (",.", "AB"));
+        assert_eq!(split_program_input("+.!"), ("+.", ""));
+        assert_eq!(split_program_input("+.!a!b"), ("+.", "a!b"));
+        assert_eq!(split_program_input("+."), ("+.", ""));
+
+        let ops = compress(&parse(program, &Language::default()));
+
+        let mut data = [0; 1];
+        let mut input_reader = UnreadableReader;
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input_reader, &mut output);
+        env.preload_input(input.as_bytes());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(output, b"A");
+    }
+
+    #[test]
+    fn test_whitespace_tokens() {
+        let language = Language::make_from_string(" -><,.\t\n").unwrap();
+        assert!(language.is_token(' '));
+        assert_eq!(parse("   ", &language).ops, [Op::Inc, Op::Inc, Op::Inc]);
+
+        let code = parse_with_source_map("  \t> \n_ ", &language);
+        assert_eq!(code.ops, [Op::Inc, Op::Inc, Op::LoopStart, Op::IncPtr, Op::Inc, Op::LoopEnd, Op::Inc]);
+        assert_eq!(code.source_map(), Some(&[0, 1, 2, 3, 4, 5, 7][..]));
+        assert_eq!(check_brackets("\t\n\n", &language), Err(ParseError::UnmatchedLoopEnd { offset: 2 }));
+
+        // Comments skip whitespace tokens, and expectation lines keep the line break ending them
+        let language = language.with_comment_delimiters('{', '}').unwrap().with_expectations();
+        assert_eq!(parse("\t\t{   }\n-- expect: x\n", &language).ops, [Op::LoopStart, Op::LoopStart, Op::LoopEnd, Op::LoopEnd]);
+        assert_eq!(language.to_string(), " -><,.\t\n");
+    }
+
+    #[test]
+    fn test_extract_expectations() {
+        let source = "-- expect: Hi\n++++++++[>+++++++++<-]>.+.\n-- expect:  two words \n not -- expect: this\n";
+        assert_eq!(extract_expectations(source), vec!["Hi".to_owned(), "two words".to_owned()]);
+        assert_eq!(extract_expectations("+."), Vec::<String>::new());
+
+        let language = Language::default().with_expectations();
+        assert_eq!(parse(source, &language), parse("++++++++[>+++++++++<-]>.+. not -- this", &language));
+        assert_eq!(check_brackets("-- expect: ]\n[]", &language), Ok(()));
+
+        // Without expectations the lines are parsed like any other
+        let language = Language::default();
+        assert_eq!(parse("-- expect: +\n", &language).ops, [Op::Dec, Op::Dec, Op::Inc]);
+        assert!(check_brackets("-- expect: ]\n[]", &language).is_err());
+    }
+
+    #[test]
+    fn test_expand_rle() {
+        let language = Language::default();
+
+        assert_eq!(expand_rle("3+2> cell 7 0-", &language).unwrap(), "+++>> cell 7 ");
+        assert_eq!(expand_rle("10", &language).unwrap(), "10");
+
+        // Counts are ambiguous when digits are tokens
+        let digits = Language::make_from_string("12345678").unwrap();
+        assert_eq!(expand_rle("3a1", &digits), None);
+        assert_eq!(compress(&parse("11", &digits)).to_rle_source(&digits), None);
+    }
+
+    #[test]
+    fn test_compress_run() {
+        // hello.bf
+        let language = Language::default();
+
+        let ops = parse(HELLO_BF, &language);
+        let compressed_ops = compress(&ops);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        run(&compressed_ops, &mut env).unwrap();
+
+        let output_string = from_utf8(&output[0..13]).expect("Encoding error");
+        assert_eq!(output_string, "Hello World!\n");
+    }
+
+    #[test]
+    fn test_input() {
+        // hello.bf
+        let source = ",.,.,.".to_string();
+        let language = Language::default();
+
+        let ops = parse(&source, &language);
+        let compressed_ops = compress(&ops);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![b'a', b'b', b'c']);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        run(&compressed_ops, &mut env).unwrap();
+
+        let output_string = from_utf8(&output[0..3]).expect("Encoding error");
+        assert_eq!(output_string, "abc");
+    }
+
+    #[test]
+    fn test_run_options() {
+        let language = Language::default();
+
+        let ops = parse(",-.+[]", &language);
+        let compressed_ops = compress(&ops);
+
+        let options = RunOptions::builder()
+            .eof_mode(EofMode::Max)
+            .cell_overflow(CellOverflow::Saturate)
+            .output_format(OutputFormat::Decimal)
+            .step_limit(Some(5))
+            .build();
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+
+        // EOF stores 255, `+` saturates instead of wrapping, then the loop
+        // never ends and is stopped by the step limit
+        assert_eq!(run(&compressed_ops, &mut env), Err(RuntimeError::StepLimitExceeded { pc: 5, source_offset: None }));
+        assert_eq!(env.steps(), 5);
+        assert_eq!(env.read_data(), 255);
+        assert_eq!(output, b"254");
+
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let result = execute(",-.+[]", &language, &options, &mut input, &mut output);
+        assert_eq!(result, Err(RuntimeError::StepLimitExceeded { pc: 5, source_offset: Some(5) }));
+    }
+
+    #[test]
+    fn test_grade() {
+        let language = Language::default();
+
+        assert_eq!(grade(",[.,]", &language, b"hi", 1, 100), GradeResult {
+            terminated: true,
+            steps: 8,
+            output: b"hi".to_vec(),
+            violation: None,
+        });
+
+        let result = grade("+.>+.>+.", &language, b"", 2, 100);
+        assert!(!result.terminated);
+        assert_eq!(result.output, [1, 1]);
+        assert_eq!(result.violation, Some(RuntimeError::PointerOverflow { index: 2, tape_len: 2, pc: 5, source_offset: Some(5) }));
+
+        let result = grade("+[]", &language, b"", 1, 100);
+        assert!(!result.terminated);
+        assert_eq!(result.steps, 100);
+        assert_eq!(result.violation, Some(RuntimeError::StepLimitExceeded { pc: 2, source_offset: Some(2) }));
+    }
+
+    #[test]
+    fn test_programs_equivalent() {
+        let language = Language::default();
+        let inputs: [&[u8]; 2] = [b"", b"xyz"];
+
+        let print_a = "++++++++[>++++++++<-]>+.[-]";
+        assert!(programs_equivalent(print_a, "+++++[>+++++++++++++<-]>.[-]", &language, &inputs, 1000));
+        assert!(programs_equivalent(print_a, print_a, &language, &inputs, 1000));
+        // Same output, but the cell holding 'A' isn't cleared
+        assert!(!programs_equivalent(print_a, "+++++[>+++++++++++++<-]>.", &language, &inputs, 1000));
+        assert!(programs_equivalent(",.", "+[-],.", &language, &inputs, 1000));
+        assert!(!programs_equivalent(",.", ".", &language, &inputs, 1000));
+        assert!(!programs_equivalent(print_a, print_a, &language, &inputs, 10));
+    }
+
+    #[test]
+    fn test_execute_str() {
+        assert_eq!(execute_str(",.,.", "ab"), Ok("ab".to_owned()));
+        assert_eq!(execute_str(HELLO_BF, ""), Ok("Hello World!\n".to_owned()));
+        // Bytes are put as the characters of the same code points
+        assert_eq!(execute_str("-.", ""), Ok("ÿ".to_owned()));
+        assert_eq!(execute_str(",.", "é"), Ok("Ã".to_owned()));
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        let language = Language::default();
+        assert_eq!(compression_ratio("", &language), 1.0);
+        assert_eq!(compression_ratio("+>.", &language), 1.0);
+        assert!(compression_ratio(&format!("{}.{}.", "+".repeat(100), ">".repeat(50)), &language) < 0.1);
+    }
+
+    #[test]
+    fn test_execute_flushes() {
+        let language = Language::default();
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+
+        // Output it ends with has no newline to flush a line buffer
+        let mut output = std::io::BufWriter::new(Vec::new());
+        execute("++++++++[>++++++++<-]>+.+.", &language, &options, &mut std::io::empty(), &mut output).unwrap();
+        assert_eq!(output.get_ref(), b"AB");
+
+        let mut output = std::io::BufWriter::new(Vec::new());
+        assert_eq!(execute("++++++++[>++++++++<-]>+.+.<<", &language, &options, &mut std::io::empty(), &mut output),
+                   Err(RuntimeError::PointerUnderflow { index: -1, pc: 12, source_offset: Some(26) }));
+        assert_eq!(output.get_ref(), b"AB");
+    }
+
+    #[test]
+    fn test_dump_tape() {
+        let language = Language::default();
+
+        let ops = parse("+>++>+++<", &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&ops, &mut env).unwrap();
+
+        let mut dump = Vec::new();
+        env.dump_tape(&mut dump, None).unwrap();
+        assert_eq!(from_utf8(&dump).unwrap(), "00000000: 01 02 03\n");
+
+        let mut dump = Vec::new();
+        env.dump_tape(&mut dump, Some(1..20)).unwrap();
+        assert_eq!(from_utf8(&dump).unwrap(), "00000001: 02 03 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n00000011: 00 00 00\n");
+    }
+
+    #[test]
+    fn test_format_tape_grid() {
+        let mut data = [1, 2, 3, 0, 0, 255, 7, 8, 9, 10, 11];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_pointer(5).unwrap();
+        assert_eq!(env.format_tape_grid(4, 0..11), concat!(
+            " 0:   1    2    3    0\n",
+            " 4:   0 [255]   7    8\n",
+            " 8:   9   10   11\n",
+        ));
+        assert_eq!(env.format_tape_grid(4, 5..6), "5:[255]\n");
+        assert_eq!(env.format_tape_grid(4, 20..30), "");
+    }
+
+    #[test]
+    fn test_run_with_hook() {
+        let language = Language::default();
+
+        let ops = parse("++++++++++>+", &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+
+        let mut seen = 0;
+        run_with_hook(&ops, &mut env, |op, env| {
+            assert_eq!(*op, Op::Inc);
+            seen += 1;
+            if env.cell(0) == Some(5) { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        }).unwrap();
+
+        assert_eq!(seen, 6);
+        assert_eq!(env.pc(), 5);
+        assert_eq!(env.cell(0), Some(5));
+        assert_eq!(env.cell(1), Some(0));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let language = Language::default();
+
+        let ops = parse_with_source_map("+>+>>,<+", &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![b'a']);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_watchpoint(2, |old, new| old != new);
+
+        assert_eq!(run(&ops, &mut env), Err(RuntimeError::Watchpoint { index: 2, old: 0, new: 1, pc: 7, source_offset: Some(7) }));
+        assert_eq!(env.cell(2), Some(0));
+        assert_eq!(env.cell(3), Some(b'a'));
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![b'a']);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_watchpoint(3, |_, new| new == b'a');
+
+        assert_eq!(run(&ops, &mut env), Err(RuntimeError::Watchpoint { index: 3, old: 0, new: 97, pc: 5, source_offset: Some(5) }));
+    }
+
+    /// Writer which records what's written and how many times it's flushed
+    #[derive(Default)]
+    struct RecordingWriter {
+        written: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for RecordingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_once() {
+        let language = Language::default();
+
+        let ops = parse(HELLO_BF, &language);
+
+        let mut data = [0; BUF_SIZE];
+        let mut input = Cursor::new(vec![]);
+        let mut output = RecordingWriter::default();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&ops, &mut env).unwrap();
+        env.finish().unwrap();
+
+        assert_eq!(from_utf8(&output.written).unwrap(), "Hello World!\n");
+        assert_eq!(output.flushes, 1);
+    }
+
+    /// Reader and writer logging reads, writes and flushes in the order they happen
+    #[derive(Clone, Default)]
+    struct EventLog(std::rc::Rc<std::cell::RefCell<Vec<&'static str>>>);
+
+    impl Read for EventLog {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().push("read");
+            buf[0] = b'x';
+            Ok(1)
+        }
+    }
+
+    impl Write for EventLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().push("write");
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.borrow_mut().push("flush");
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush_before_read() {
+        let language = Language::default();
+
+        let ops = parse("+.,.", &language);
+
+        let log = EventLog::default();
+        let mut data = [0; BUF_SIZE];
+        let mut input = log.clone();
+        let mut output = log.clone();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&ops, &mut env).unwrap();
+        env.finish().unwrap();
+
+        assert_eq!(*log.0.borrow(), ["write", "flush", "read", "write", "flush"]);
+
+        // Preloaded input doesn't block, so there's nothing to flush for
+        let log = EventLog::default();
+        let mut input = log.clone();
+        let mut output = log.clone();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.preload_input(b"x");
+        run(&ops, &mut env).unwrap();
+
+        assert_eq!(*log.0.borrow(), ["write", "write"]);
+    }
+
+    /// Writer which always fails
+    struct BrokenWriter;
+
+    impl Write for BrokenWriter {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn test_io_error() {
+        let language = Language::default();
+
+        let ops = parse("+.", &language);
+
+        let mut data = [0; BUF_SIZE];
         let mut input = Cursor::new(vec![]);
+        let mut output = BrokenWriter;
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run(&ops, &mut env), Err(RuntimeError::Io { kind: std::io::ErrorKind::BrokenPipe, pc: 1, source_offset: None }));
+        assert!(env.finish().is_err());
+    }
+
+    /// Reader which fails the test if it's ever read from
+    struct UnreadableReader;
+
+    impl Read for UnreadableReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            panic!("Reader must not be touched");
+        }
+    }
+
+    #[test]
+    fn test_read_char_advances() {
+        let mut data = [0; 3];
+        let mut input = Cursor::new(b"abcd".to_vec());
         let mut output = Vec::new();
 
         let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&parse(",>,>,", &Language::default()), &mut env).unwrap();
+        assert_eq!(data, *b"abc");
+        // The byte after the ones read is left for the next reader
+        assert_eq!(input.position(), 3);
+    }
 
-        run(&ops, &mut env);
+    struct CountingReader {
+        bytes: Vec<u8>,
+        reads: usize,
+        consumed: usize,
+    }
 
-        let output_string = from_utf8(&output[0..13]).expect("Encoding error");
-        assert_eq!(output_string, "Hello World!\n");
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            let n = buf.len().min(self.bytes.len() - self.consumed);
+            buf[..n].copy_from_slice(&self.bytes[self.consumed..self.consumed + n]);
+            self.consumed += n;
+            Ok(n)
+        }
     }
 
     #[test]
-    fn test_run_safe() {
-        let language = Language::default();
+    fn test_read_char_consumes_one_byte() {
+        let mut data = [0; 2];
+        let mut input = CountingReader { bytes: b"xyz".to_vec(), reads: 0, consumed: 0 };
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&parse(",.", &Language::default()), &mut env).unwrap();
+        assert_eq!(env.bytes_read(), 1);
+        run_sequence(&[parse(">,.", &Language::default())], &mut env).unwrap();
+        assert_eq!((input.reads, input.consumed), (2, 2));
+        assert_eq!(output, b"xy");
+    }
+
+    struct FailingReader;
 
-        let ops = parse(&"<<<<<<.>>>>>>.".to_string(), &language);
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"))
+        }
+    }
 
+    #[test]
+    fn test_read_error() {
         let mut data = [0; 1];
-        let mut input = Cursor::new(vec![]);
+        let mut input = FailingReader;
         let mut output = Vec::new();
 
         let mut env = Environment::new(&mut data, &mut input, &mut output);
-
-        // Should not panic
-        run(&ops, &mut env);
+        assert_eq!(run(&parse("+,", &Language::default()), &mut env),
+                   Err(RuntimeError::Io { kind: std::io::ErrorKind::PermissionDenied, pc: 1, source_offset: None }));
     }
 
     #[test]
-    fn test_compress() {
-        let source = "+++++[>>>----<<<[[..]],,]".to_string();
+    fn test_input_immediate() {
         let language = Language::default();
 
-        let ops = parse(&source, &language);
-        let compressed_ops = compress(&ops);
+        let ops = parse(",.,.,.", &language);
 
-        assert_eq!(compressed_ops.ops, [
-            CompressedOp::Add(5),
-            CompressedOp::LoopStart,
-            CompressedOp::Forward(3),
-            CompressedOp::Sub(4),
-            CompressedOp::Back(3),
-            CompressedOp::LoopStart,
-            CompressedOp::LoopStart,
-            CompressedOp::PutChar,
-            CompressedOp::PutChar,
-            CompressedOp::LoopEnd,
-            CompressedOp::LoopEnd,
-            CompressedOp::GetChar,
-            CompressedOp::GetChar,
-            CompressedOp::LoopEnd,
-        ]);
+        let mut data = [0; BUF_SIZE];
+        let mut input = UnreadableReader;
+        let mut output = Vec::new();
 
-        assert_eq!(compressed_ops.jump_table[1], 14);
-        assert_eq!(compressed_ops.jump_table[13], 2);
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_input_availability(InputAvailability::Immediate);
+        env.preload_input(b"ab");
 
-        assert_eq!(compressed_ops.jump_table[5], 11);
-        assert_eq!(compressed_ops.jump_table[10], 6);
+        // Reading past the preloaded input is EOF instead of blocking
+        run(&ops, &mut env).unwrap();
 
-        assert_eq!(compressed_ops.jump_table[6], 10);
-        assert_eq!(compressed_ops.jump_table[9], 7);
+        assert_eq!(output, [b'a', b'b', 0]);
     }
 
     #[test]
-    fn test_compress_run() {
-        // hello.bf
+    fn test_input_preloaded_then_reader() {
         let language = Language::default();
 
-        let ops = parse(&HELLO_BF.to_string(), &language);
-        let compressed_ops = compress(&ops);
+        let ops = parse(",.,.,.", &language);
 
         let mut data = [0; BUF_SIZE];
-        let mut input = Cursor::new(vec![]);
+        let mut input = Cursor::new(vec![b'c']);
         let mut output = Vec::new();
 
         let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.preload_input(b"ab");
 
-        run(&compressed_ops, &mut env);
+        run(&ops, &mut env).unwrap();
 
-        let output_string = from_utf8(&output[0..13]).expect("Encoding error");
-        assert_eq!(output_string, "Hello World!\n");
+        let output_string = from_utf8(&output[0..3]).expect("Encoding error");
+        assert_eq!(output_string, "abc");
     }
 
     #[test]
-    fn test_input() {
-        // hello.bf
-        let source = ",.,.,.".to_string();
+    fn test_modulus() {
         let language = Language::default();
+        let ops = compress(&parse("+++++>++++<++++>-----", &language));
 
-        let ops = parse(&source, &language);
-        let compressed_ops = compress(&ops);
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().modulus(Some(7)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        run(&ops, &mut env).unwrap();
+        assert_eq!(data, [2, 6]);
+
+        let mut data = [5, 0];
+        let options = RunOptions::builder().modulus(Some(7)).cell_overflow(CellOverflow::Saturate).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        run(&compress(&parse("++++>-", &language)), &mut env).unwrap();
+        assert_eq!(data, [6, 0]);
+
+        let mut data = [5];
+        let options = RunOptions::builder().modulus(Some(7)).cell_overflow(CellOverflow::Error).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert_eq!(run(&parse("+++", &language), &mut env), Err(RuntimeError::CellOverflow { pc: 1, source_offset: None }));
+        assert_eq!(data, [6]);
+    }
+
+    #[test]
+    fn test_trap_uninitialized() {
+        let language = Language::default();
+
+        let mut data = [0; 8];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().trap_uninitialized(true).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+        assert_eq!(run(&compress(&parse_with_source_map("+>,>>->>[.]", &language)), &mut env),
+                   Err(RuntimeError::UninitializedRead { index: 5, pc: 6, source_offset: Some(8) }));
+
+        // Writes mark cells, even back to zero
+        let mut data = [0; 8];
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+        run(&compress(&parse("+-[.]>>>>>,.[-]", &language)), &mut env).unwrap();
+
+        let mut data = [0; 8];
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, RunOptions::default());
+        run(&compress(&parse(">>>>>[.]", &language)), &mut env).unwrap();
+
+        let mut tape = BiTape::<u8>::new(2);
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, options);
+        // Written cells shift along with the tape growing to the front
+        assert_eq!(run(&parse("+<>.<.", &language), &mut env),
+                   Err(RuntimeError::UninitializedRead { index: 0, pc: 5, source_offset: None }));
+    }
+
+    #[test]
+    fn test_output_hex() {
+        let mut input = Cursor::new(vec![]);
+
+        for (data, expected) in [(0xab, "ab "), (0x05, "05 "), (0, "00 ")] {
+            let mut data = [data];
+            let mut output = Vec::new();
+            let options = RunOptions::builder().output_format(OutputFormat::Hex).build();
+            let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+            run(&parse(".", &Language::default()), &mut env).unwrap();
+            assert_eq!(env.bytes_written(), 3);
+            assert_eq!(output, expected.as_bytes());
+        }
+
+        let mut data = [0x1234u16, 0x100];
+        let mut output = Vec::new();
+        let options = RunOptions::builder().output_format(OutputFormat::Hex).max_output(Some(8)).build();
+        let mut env = Environment::with_cells(&mut data, &mut input, &mut output, options);
+        assert_eq!(run(&parse(".>.", &Language::default()), &mut env).err().map(|err| err.pc()), Some(2));
+        assert_eq!(output, b"1234 ");
+    }
+
+    #[test]
+    fn test_max_cells() {
+        let ops = parse_with_source_map("+[>+]", &Language::default());
+
+        let mut data = [0; 8];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().max_cells(Some(5)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        let result = run(&ops, &mut env);
+        assert_eq!(result, Err(RuntimeError::MemoryLimitExceeded { limit: 5, pc: 2, source_offset: Some(2) }));
+        assert_eq!(data, [1, 1, 1, 1, 1, 0, 0, 0]);
+
+        // Moving back over touched cells stays within the limit
+        let ops = parse("+++[>>+<<-]>><<>>", &Language::default());
+        let mut data = [0; 8];
+        let options = RunOptions::builder().max_cells(Some(3)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert_eq!(run(&ops, &mut env), Ok(()));
+        assert_eq!(data[..3], [0, 0, 3]);
+
+        // Moves by several cells count the cells passed, as separate moves would
+        let language = Language::default();
+        for limit in 3..5 {
+            let options = RunOptions::builder().max_cells(Some(limit)).build();
+            let uncompressed = run(&parse(">>>", &language), &mut Environment::with_options(&mut data, &mut input, &mut output, options.clone()));
+            let compressed = run(&compress(&parse(">>>", &language)), &mut Environment::with_options(&mut data, &mut input, &mut output, options));
+            assert_eq!(uncompressed.is_ok(), limit == 4);
+            assert_eq!(compressed.is_ok(), limit == 4);
+        }
+
+        // Only cells from where the pointer starts count
+        let mut data = [0; 8];
+        let mut env = Environment::with_pointer(&mut data, &mut input, &mut output, 3).unwrap();
+        env.set_options(RunOptions::builder().max_cells(Some(2)).build());
+        let result = run(&parse(">+<<", &language), &mut env);
+        assert_eq!(result, Err(RuntimeError::MemoryLimitExceeded { limit: 2, pc: 3, source_offset: None }));
+        assert_eq!(data[4], 1);
+    }
+
+    #[test]
+    fn test_max_output() {
+        let ops = compress(&parse_with_source_map("+++++++[>++++++++<-]>+[.]", &Language::default()));
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().max_output(Some(5)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        let result = run(&ops, &mut env);
+        assert_eq!(result, Err(RuntimeError::OutputLimitExceeded { pc: 10, source_offset: Some(23) }));
+        assert_eq!(env.bytes_written(), 5);
+        assert_eq!(output, b"99999");
+
+        // Decimal output counts digits
+        let ops = parse("+++++++++.+.", &Language::default());
+
+        let mut data = [0; 1];
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().max_output(Some(2)).output_format(OutputFormat::Decimal).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert!(run(&ops, &mut env).is_err());
+        assert_eq!(output, b"9");
+    }
+
+    #[test]
+    fn test_decimal_separator_width() {
+        let ops = compress(&parse("++++++++[>++++++++<-]>+.+.", &Language::default()));
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder()
+            .output_format(OutputFormat::Decimal)
+            .decimal_separator(" ")
+            .decimal_width(Some(3))
+            .build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options.clone());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(output, b"065 066 ");
+
+        // Padding and separators count towards the output limit
+        let mut data = [0; 2];
+        let mut output = Vec::new();
+        let options = RunOptions { max_output: Some(7), ..options };
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert!(run(&ops, &mut env).is_err());
+        assert_eq!(output, b"065 ");
+    }
+
+    #[test]
+    fn test_max_input() {
+        let ops = compress(&parse_with_source_map("+[,.]", &Language::default()));
+
+        let mut data = [0; 1];
+        let mut input = Cursor::new(b"abcdef".to_vec());
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().max_input(Some(3)).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        env.preload_input(b"x");
+        let result = run(&ops, &mut env);
+        assert_eq!(result, Err(RuntimeError::InputLimitExceeded { pc: 2, source_offset: Some(2) }));
+        assert_eq!(env.bytes_read(), 3);
+        assert_eq!(output, b"xab");
+    }
+
+    #[test]
+    fn test_io_word() {
+        let ops = parse(",>,<.>.>,.", &Language::default());
+
+        let mut data = [0u16; 3];
+        let mut input = Cursor::new(vec![0x34, 0x12, 0xff, 0x00, 0x7f]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().io_width(IoWidth::Word).build();
+        let mut env = Environment::with_cells(&mut data, &mut input, &mut output, options);
+        run(&ops, &mut env).unwrap();
+
+        assert_eq!(data, [0x1234, 0x00ff, 0x007f]);
+        assert_eq!(output, [0x34, 0x12, 0xff, 0x00, 0x7f, 0x00]);
+
+        // Odd EOF keeps the high byte of the cell
+        let ops = parse(",", &Language::default());
+        let mut data = [0xabcdu16];
+        let mut input = Cursor::new(vec![0x01]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().io_width(IoWidth::Word).eof_mode(EofMode::Unchanged).build();
+        let mut env = Environment::with_cells(&mut data, &mut input, &mut output, options);
+        run(&ops, &mut env).unwrap();
+        assert_eq!(data, [0xab01]);
+    }
+
+    #[test]
+    fn test_input_echo() {
+        let language = Language::default();
+
+        let ops = parse(",+.,", &language);
 
         let mut data = [0; BUF_SIZE];
-        let mut input = Cursor::new(vec![b'a', b'b', b'c']);
+        let mut input = Cursor::new(vec![b'a']);
         let mut output = Vec::new();
 
         let mut env = Environment::new(&mut data, &mut input, &mut output);
+        env.set_echo_input(true);
 
-        run(&compressed_ops, &mut env);
+        // EOF isn't echoed
+        run(&ops, &mut env).unwrap();
 
-        let output_string = from_utf8(&output[0..3]).expect("Encoding error");
-        assert_eq!(output_string, "abc");
+        assert_eq!(output, b"ab");
     }
 
     #[test]
@@ -528,7 +3840,13 @@ mod tests {
             put_char: 'e',
             get_char: 'f',
             loop_start: 'g',
-            loop_end: 'h'
+            loop_end: 'h',
+            comment_open: None,
+            comment_close: None,
+            assert: None,
+            nop: None,
+            put_pointer: None,
+            expectations: false,
         };
 
         let source = "abcdefgh".to_string();
@@ -546,9 +3864,34 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn test_detect_language() {
+        let abc = Language::make_from_string("abcdefgh").unwrap();
+        let candidates = [Language::default(), abc];
+
+        let detected = detect_language(HELLO_BF, &candidates).unwrap();
+        assert_eq!(detected.loop_start, '[');
+
+        let hello_abc = "aaaaaaaagcaaaagcaacaaacaaacaddddbhcacacbccagdhdbhccfcbbbfaaaaaaaffaaafccfdbfdfaaafbbbbbbfbbbbbbbbfccafcaaf";
+        let detected = detect_language(hello_abc, &candidates).unwrap();
+        assert_eq!(detected.loop_start, 'g');
+
+        // Denser under the custom language, but its loops aren't balanced
+        let detected = detect_language("++[-] gah", &candidates).unwrap();
+        assert_eq!(detected.loop_start, '[');
+
+        // Brackets in comments don't count, as they aren't parsed
+        let commented = Language::default().with_comment_delimiters('{', '}').unwrap();
+        let candidates_with_comments = [Language::default(), commented.clone()];
+        assert_eq!(detect_language("+[-]{ ] }", &candidates_with_comments), Some(&commented));
+
+        assert!(detect_language("no tokens here", &candidates[..1]).is_none());
+        assert!(detect_language("", &candidates).is_none());
+    }
+
     #[test]
     fn test_language_from_string() {
-        let language = Language::make_from_string(&"abcdefgh".to_string());
+        let language = Language::make_from_string("abcdefgh");
 
         assert!(language.is_some());
 
@@ -564,6 +3907,141 @@ mod tests {
         assert_eq!(language.loop_end, 'h');
     }
 
+    #[test]
+    fn test_language_documented_order() {
+        // Same order as the CLI help, where `,` reads and `.` puts
+        let language = Language::make_from_string("+-><,.[]").unwrap();
+        assert_eq!(parse(".,", &language).ops, [Op::PutChar, Op::GetChar]);
+
+        let language = Language::make_from_string("abcdefgh").unwrap();
+        assert_eq!(parse("fe", &language).ops, [Op::PutChar, Op::GetChar]);
+    }
+
+    #[test]
+    fn test_language_is_default() {
+        assert!(Language::default().is_default());
+        assert!(Language::make_from_string("+-><,.[]").unwrap().is_default());
+        assert!(!Language::make_from_string("+-><.,[]").unwrap().is_default());
+        assert!(!Language::make_from_string("abcdefgh").unwrap().is_default());
+    }
+
+    #[test]
+    fn test_language_clone() {
+        let language = Language::make_from_string("abcdefgh").unwrap();
+        let cloned = language.clone();
+        assert_eq!(cloned, language);
+        assert_ne!(cloned, Language::default());
+        assert!(format!("{:?}", cloned).contains("inc: 'a'"));
+    }
+
+    #[test]
+    fn test_language_comments() {
+        let language = Language::default().with_comment_delimiters('{', '}').unwrap();
+
+        assert_eq!(parse("{+++}+", &language).ops, [Op::Inc]);
+        assert_eq!(parse_with_source_map("-{[}+{>}<{,", &language).source_map(), Some(&[0, 4, 8][..]));
+        assert_eq!(check_brackets("[{]}]", &language), Ok(()));
+        assert!(!language.is_default());
+
+        assert_eq!(parse("{+++}+", &Language::default()).ops.len(), 4);
+        assert!(Language::default().with_comment_delimiters('[', '}').is_none());
+        assert!(Language::default().with_comment_delimiters('{', '.').is_none());
+    }
+
+    #[test]
+    fn test_language_non_ascii() {
+        let language = Language::make_from_string("＋－＞＜，．【】").unwrap();
+        let source = "＋＋＋【－＞＋＋＋＜】＞＋，．．x";
+
+        let code = parse_with_source_map(source, &language);
+        assert_eq!(code.ops[..4], [Op::Inc, Op::Inc, Op::Inc, Op::LoopStart]);
+        assert_eq!(code.jump_table[3], 11);
+        assert_eq!(code.jump_table[10], 4);
+        // Offsets are in bytes, three for each of these characters
+        assert_eq!(code.source_offset(3), Some(9));
+        assert_eq!(&source[code.source_offset(14).unwrap()..], "．．x");
+        assert!(code.validate().is_ok());
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(b"!".to_vec());
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&compress(&code), &mut env).unwrap();
+        assert_eq!(output, b"!!");
+        assert_eq!(data, [0, b'!']);
+
+        let rle = compress(&code).to_rle_source(&language).unwrap();
+        assert_eq!(rle, "3＋【－＞3＋＜】＞＋，．．");
+        assert_eq!(parse(&expand_rle(&rle, &language).unwrap(), &language).ops, code.ops);
+
+        assert_eq!(check_brackets("【【】", &language), Err(ParseError::UnmatchedLoopStart { offset: 0 }));
+        assert_eq!(check_brackets("＋】", &language), Err(ParseError::UnmatchedLoopEnd { offset: 3 }));
+        assert_eq!(detect_language(source, &[Language::default(), language.clone()]), Some(&language));
+        assert_eq!(language.to_string(), "＋－＞＜，．【】");
+    }
+
+    #[test]
+    fn test_assert() {
+        let language = Language::default().with_assert('?').unwrap();
+        assert_eq!(parse("+?", &language).ops, [Op::Inc, Op::Assert]);
+        assert_eq!(parse("+?", &Language::default()).ops, [Op::Inc]);
+        assert!(Language::default().with_assert('+').is_none());
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&compress(&parse("+?", &language)), &mut env).unwrap();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run(&parse_with_source_map(">?", &language), &mut env),
+                   Err(RuntimeError::AssertionFailed { cell: 1, pc: 1, source_offset: Some(1) }));
+
+        let mut data = [0; 1];
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let compressed = compress(&parse("?", &language));
+        assert_eq!(compressed.ops, [CompressedOp::Assert]);
+        assert_eq!(run(&compressed, &mut env), Err(RuntimeError::AssertionFailed { cell: 0, pc: 0, source_offset: None }));
+        assert_eq!(compressed.to_rle_source(&language).unwrap(), "?");
+    }
+
+    #[test]
+    fn test_nop() {
+        let language = Language::default().with_nop('#').unwrap();
+        let code = parse_with_source_map("+#+", &language);
+        assert_eq!(code.ops, [Op::Inc, Op::Nop, Op::Inc]);
+        assert_eq!(parse("+#+", &Language::default()).ops, [Op::Inc, Op::Inc]);
+        assert!(Language::default().with_nop('+').is_none());
+        assert!(Language::default().with_assert('#').unwrap().with_nop('#').is_none());
+
+        let mut data = [0; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert!(step(&code, &mut env).unwrap());
+        assert!(step(&code, &mut env).unwrap());
+        assert_eq!((env.pc(), env.read_data()), (2, 1));
+        run(&code, &mut env).unwrap();
+        assert_eq!(env.read_data(), 2);
+
+        let compressed = compress(&code);
+        assert_eq!(compressed.ops, [CompressedOp::Add(2)]);
+        assert_eq!(compressed.source_map(), Some(&[0][..]));
+        assert_eq!(compress(&parse("#[#-#]#", &language)).ops, [CompressedOp::LoopStart, CompressedOp::Sub(1), CompressedOp::LoopEnd]);
+        assert!(compress(&parse("#[#-#]#", &language)).validate().is_ok());
+    }
+
+    #[test]
+    fn test_language_to_string() {
+        for s in ["abcdefgh", "+-><,.[]", "あいうえおかきく"] {
+            assert_eq!(Language::make_from_string(s).unwrap().to_string(), s);
+        }
+        assert_eq!(Language::default().to_string(), "+-><,.[]");
+    }
+
     #[test]
     fn test_environment_new() {
         let mut input = Cursor::new("");
@@ -580,4 +4058,128 @@ mod tests {
 
         // TODO: Check reader, writer, and data
     }
+
+    /// Small xorshift generator so the property tests are reproducible
+    /// without pulling in a dependency.
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    /// Generate a random program with balanced brackets.
+    fn gen_program(rng: &mut XorShift, depth: usize, budget: &mut usize) -> String {
+        const SIMPLE: [char; 6] = ['+', '-', '>', '<', ',', '.'];
+        let mut source = String::new();
+
+        while *budget > 0 && rng.below(8) != 0 {
+            if depth > 0 && rng.below(5) == 0 {
+                *budget -= 1;
+                source.push('[');
+                source.push_str(&gen_program(rng, depth - 1, budget));
+                source.push(']');
+            } else {
+                // Emit runs so compression has something to merge
+                let ch = SIMPLE[rng.below(SIMPLE.len() as u64) as usize];
+                let run = 1 + rng.below(4) as usize;
+                for _ in 0..run.min(*budget) {
+                    *budget -= 1;
+                    source.push(ch);
+                }
+            }
+        }
+
+        source
+    }
+
+    /// Run until the program ends or `max_steps` ops are executed.
+    /// Returns false if the program didn't finish in time.
+    fn run_bounded<R: Read, W: Write, O: Runnable>(code: &Code<O>, env: &mut Environment<R, W>, max_steps: usize) -> bool {
+        for _ in 0..max_steps {
+            if env.pc >= code.ops.len() {
+                return true;
+            }
+            code.ops[env.pc].run(code, env).expect("Runtime error");
+        }
+        env.pc >= code.ops.len()
+    }
+
+    /// Output and final tape of a run
+    type RunResult = (Vec<u8>, Vec<u8>);
+
+    /// Run the source both without and with compression.
+    /// Returns None if the program didn't halt.
+    fn run_both(source: &str, input: &[u8]) -> Option<(RunResult, RunResult)> {
+        const TAPE_SIZE: usize = 16;
+        const MAX_STEPS: usize = 10_000;
+
+        let language = Language::default();
+        let ops = parse(source, &language);
+        let compressed_ops = compress(&ops);
+
+        let mut data = vec![0; TAPE_SIZE];
+        let mut reader = Cursor::new(input.to_vec());
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut reader, &mut output);
+        if !run_bounded(&ops, &mut env, MAX_STEPS) {
+            return None;
+        }
+        let plain = (output, data);
+
+        let mut data = vec![0; TAPE_SIZE];
+        let mut reader = Cursor::new(input.to_vec());
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut reader, &mut output);
+        assert!(run_bounded(&compressed_ops, &mut env, MAX_STEPS), "compressed run of {:?} didn't halt", source);
+        let compressed = (output, data);
+
+        Some((plain, compressed))
+    }
+
+    fn assert_compress_preserves(source: &str, input: &[u8]) {
+        if let Some((plain, compressed)) = run_both(source, input) {
+            assert_eq!(plain, compressed, "compress changed behavior of {:?}", source);
+        }
+    }
+
+    #[test]
+    fn test_compress_preserves_semantics() {
+        let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+        let mut halted = 0;
+
+        for _ in 0..500 {
+            let mut budget = 64;
+            let source = gen_program(&mut rng, 3, &mut budget);
+            let input = (0..8).map(|_| rng.below(256) as u8).collect::<Vec<u8>>();
+
+            if let Some((plain, compressed)) = run_both(&source, &input) {
+                assert_eq!(plain, compressed, "compress changed behavior of {:?}", source);
+                halted += 1;
+            }
+        }
+
+        // Make sure we're not vacuously passing on programs that never halt
+        assert!(halted > 100);
+    }
+
+    #[test]
+    fn test_compress_preserves_semantics_edge_cases() {
+        // Empty program
+        assert_compress_preserves("", b"");
+        // Nested loops
+        assert_compress_preserves("+++[>++[>+++<-]<-]>>.", b"");
+        // I/O ops
+        assert_compress_preserves(",,.>,,,..<.", b"abcdef");
+        // Long runs that wrap the cell
+        assert_compress_preserves(&"+".repeat(300), b"");
+    }
 }
@@ -1,8 +1,8 @@
-use std::fs::read_to_string;
-use std::io::{stdin, stdout};
+use std::fs::{read, read_to_string, write, File};
+use std::io::{stderr, stdin, stdout, BufReader, Error, ErrorKind};
 use std::process::exit;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
 use bfk::*;
 
@@ -18,6 +18,52 @@ fn main() {
         .version("0.2.0")
         .author("Yusaku Hashimoto <nonowarn@gmail.com>")
         .about("Brainfuck Interpreter")
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            App::new("compile")
+                .about("Compile a program to bytecode")
+                .arg(
+                    Arg::with_name("PROGRAM")
+                        .help("Brainfuck program to compile")
+                        .required(true)
+                        .index(1)
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .help("Bytecode file to write")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .required(true)
+                )
+                .arg(
+                    Arg::with_name("language")
+                        .help("Language to compile as, the same as for running")
+                        .short('l')
+                        .long("language")
+                        .takes_value(true)
+                )
+                .arg(
+                    Arg::with_name("opt_level")
+                        .help("0 only compresses, 1 optimizes once and 2 optimizes until nothing changes")
+                        .short('O')
+                        .long("opt-level")
+                        .takes_value(true)
+                        .default_value("2")
+                        .possible_values(["0", "1", "2"])
+                )
+        )
+        .subcommand(
+            App::new("run")
+                .about("Run a bytecode file")
+                .arg(
+                    Arg::with_name("BYTECODE")
+                        .help("Bytecode file to run")
+                        .required(true)
+                        .index(1)
+                )
+        )
         .arg(
             Arg::with_name("PROGRAM")
                 .help("Brainfuck program to run")
@@ -33,39 +79,304 @@ fn main() {
         )
         .arg(
             Arg::with_name("language")
-                .help("Language to run as a string concatenated with instructions in order of: +-><,.[]")
+                .help("Language to run as a string concatenated with instructions in order of: +-><,.[] (inc, dec, inc_ptr, dec_ptr, get_char, put_char, loop_start, loop_end)")
                 .short('l')
                 .long("language")
                 .takes_value(true)
         )
         .arg(
             Arg::with_name("buffer_size")
-                .help("Tape buffer size in bytes")
+                .help("Tape buffer size in bytes [default: fitting the program if its pointer range is known, or 1 MiB]")
                 .short('b')
                 .long("buffer-size")
                 .takes_value(true)
                 .validator(is_usize)
         )
+        .arg(
+            Arg::with_name("rle")
+                .help("Expand run-length encoded counts like `10+` before running. Offsets in errors are into the expanded program")
+                .long("rle")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("bang_input")
+                .help("Read input from the program file after the first `!` before reading stdin")
+                .long("bang-input")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("stream")
+                .help("Parse the program file as it's read instead of reading it all first")
+                .long("stream")
+                .takes_value(false)
+                .conflicts_with_all(&["rle", "bang_input"])
+        )
+        .arg(
+            Arg::with_name("explain")
+                .help("Optimize compressed operations, printing which optimizations fired to stderr")
+                .long("explain")
+                .takes_value(false)
+                .conflicts_with("no_compress")
+        )
+        .arg(
+            Arg::with_name("dot")
+                .help("Print a Graphviz graph of the loops in the program instead of running it")
+                .long("dot")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("ratio")
+                .help("Print how many operations the program compresses to per operation instead of running it")
+                .long("ratio")
+                .takes_value(false)
+                .conflicts_with("stream")
+        )
+        .arg(
+            Arg::with_name("dump_tape")
+                .help("Print the tape to stderr after running")
+                .long("dump-tape")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("echo")
+                .help("Echo characters read to the output")
+                .long("echo")
+                .takes_value(false)
+        )
+        .arg(
+            Arg::with_name("eof")
+                .help("What to store when reading at EOF")
+                .long("eof")
+                .takes_value(true)
+                .possible_values(["zero", "unchanged", "max"])
+        )
+        .arg(
+            Arg::with_name("cell_overflow")
+                .help("What to do when data goes out of the cell's range")
+                .long("cell-overflow")
+                .takes_value(true)
+                .possible_values(["wrap", "saturate", "error"])
+        )
+        .arg(
+            Arg::with_name("pointer")
+                .help("What to do when the pointer moves outside of the tape")
+                .long("pointer")
+                .takes_value(true)
+                .possible_values(["clamp", "error"])
+        )
+        .arg(
+            Arg::with_name("flush")
+                .help("When to flush the output")
+                .long("flush")
+                .takes_value(true)
+                .possible_values(["byte", "finish"])
+        )
+        .arg(
+            Arg::with_name("step_limit")
+                .help("Maximum number of operations to run")
+                .long("step-limit")
+                .takes_value(true)
+                .validator(is_usize)
+        )
+        .arg(
+            Arg::with_name("max_output")
+                .help("Maximum number of bytes to output")
+                .long("max-output")
+                .takes_value(true)
+                .validator(is_usize)
+        )
+        .arg(
+            Arg::with_name("max_input")
+                .help("Maximum number of bytes to read")
+                .long("max-input")
+                .takes_value(true)
+                .validator(is_usize)
+        )
+        .arg(
+            Arg::with_name("output_format")
+                .help("How to write output")
+                .long("output-format")
+                .takes_value(true)
+                .possible_values(["raw", "decimal", "hex"])
+        )
+        .arg(
+            Arg::with_name("hex")
+                .help("Write output as hex digits, the same as --output-format hex")
+                .long("hex")
+                .takes_value(false)
+                .conflicts_with("output_format")
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("compile", matches)) => return compile(matches),
+        Some(("run", matches)) => return run_bytecode(matches),
+        _ => {}
+    }
+
     let filename = matches.value_of("PROGRAM").unwrap();
-    let buffer_size = match matches.value_of("buffer_size") {
-        None => { 1024 * 1024 }
-        Some(size) => { size.parse().expect("Positive integer") }
-    };
+    let buffer_size: Option<usize> = matches.value_of("buffer_size").map(|size| size.parse().expect("Positive integer"));
 
     let no_compress = matches.is_present("no_compress");
+    let dump_tape = matches.is_present("dump_tape");
 
-    let code = match read_to_string(filename) {
-        Ok(code) => code,
-        Err(err) => {
-            eprintln!("Error while reading {}: {}", filename, err);
-            exit(exitcode::NOINPUT);
+    let mut options = RunOptions::builder()
+        .echo_input(matches.is_present("echo"));
+    if let Some(eof) = matches.value_of("eof") {
+        options = options.eof_mode(match eof {
+            "unchanged" => EofMode::Unchanged,
+            "max" => EofMode::Max,
+            _ => EofMode::Zero,
+        });
+    }
+    if let Some(cell_overflow) = matches.value_of("cell_overflow") {
+        options = options.cell_overflow(match cell_overflow {
+            "saturate" => CellOverflow::Saturate,
+            "error" => CellOverflow::Error,
+            _ => CellOverflow::Wrap,
+        });
+    }
+    if let Some(pointer) = matches.value_of("pointer") {
+        options = options.pointer_policy(match pointer {
+            "error" => PointerPolicy::Error,
+            _ => PointerPolicy::Clamp,
+        });
+    }
+    if let Some(flush) = matches.value_of("flush") {
+        options = options.flush_policy(match flush {
+            "byte" => FlushPolicy::PerByte,
+            _ => FlushPolicy::OnFinish,
+        });
+    }
+    if let Some(step_limit) = matches.value_of("step_limit") {
+        options = options.step_limit(Some(step_limit.parse().expect("Positive integer")));
+    }
+    if let Some(max_output) = matches.value_of("max_output") {
+        options = options.max_output(Some(max_output.parse().expect("Positive integer")));
+    }
+    if let Some(max_input) = matches.value_of("max_input") {
+        options = options.max_input(Some(max_input.parse().expect("Positive integer")));
+    }
+    if let Some(output_format) = matches.value_of("output_format") {
+        options = options.output_format(match output_format {
+            "decimal" => OutputFormat::Decimal,
+            "hex" => OutputFormat::Hex,
+            _ => OutputFormat::Raw,
+        });
+    }
+    if matches.is_present("hex") {
+        options = options.output_format(OutputFormat::Hex);
+    }
+    let options = options.build();
+
+    let language = language(&matches);
+    let read_error = |err: std::io::Error| -> ! { read_error(filename, err) };
+
+    let (ops, input) = if matches.is_present("stream") {
+        let file = File::open(filename).unwrap_or_else(|err| read_error(err));
+        let ops = parse_reader(BufReader::new(file), &language).unwrap_or_else(|err| read_error(err));
+        (ops, Vec::new())
+    } else {
+        let bytes = read(filename).unwrap_or_else(|err| read_error(err));
+        if is_bytecode(&bytes) {
+            if let Some(arg) = ["no_compress", "rle", "bang_input", "ratio"].iter().find(|&&arg| matches.is_present(arg)) {
+                eprintln!("--{} doesn't apply to bytecode", arg.replace('_', "-"));
+                exit(exitcode::USAGE);
+            }
+
+            let code = decode_or_exit(filename, &bytes);
+            if matches.is_present("dot") {
+                print!("{}", code.to_dot());
+                return;
+            }
+            let code = if matches.is_present("explain") {
+                let (optimized_code, events) = optimize_with_events(&code);
+                for event in events {
+                    eprintln!("{}", event);
+                }
+                optimized_code
+            } else {
+                code
+            };
+            return run_compressed(&code, buffer_size, options, dump_tape);
+        }
+        let code = String::from_utf8(bytes).unwrap_or_else(|err| read_error(Error::new(ErrorKind::InvalidData, err)));
+
+        let (code, input) = if matches.is_present("bang_input") {
+            let (program, input) = split_program_input(&code);
+            (program.to_owned(), input.as_bytes().to_vec())
+        } else {
+            (code, Vec::new())
+        };
+
+        let code = if matches.is_present("rle") {
+            expand_rle(&code, &language).unwrap_or_else(|| {
+                eprintln!("--rle can't be used with languages which have digits as tokens");
+                exit(exitcode::USAGE);
+            })
+        } else {
+            code
+        };
+
+        if matches.is_present("ratio") {
+            println!("{}", compression_ratio(&code, &language));
+            return;
         }
+
+        (parse_with_source_map(&code, &language), input)
+    };
+
+    if matches.is_present("dot") {
+        if no_compress {
+            print!("{}", ops.to_dot());
+        } else {
+            print!("{}", compress(&ops).to_dot());
+        }
+        return;
+    }
+
+    let tape_size = buffer_size.unwrap_or_else(|| suggested_tape_size(&compress(&ops), 1024 * 1024));
+    let mut data = vec![0u8; tape_size];
+
+    let mut stdout = stdout();
+    let mut stdin = stdin();
+
+    let mut env = Environment::with_options(&mut data, &mut stdin, &mut stdout, options);
+    env.preload_input(&input);
+
+    let result = if no_compress {
+        run(&ops, &mut env)
+    } else if matches.is_present("explain") {
+        let (optimized_ops, events) = optimize_with_events(&compress(&ops));
+        for event in events {
+            eprintln!("{}", event);
+        }
+        run(&optimized_ops, &mut env)
+    } else {
+        let compressed_ops = compress(&ops);
+        run(&compressed_ops, &mut env)
     };
 
-    let language = match matches.value_of("language") {
-        Some(language_str) => match Language::make_from_string(&language_str.to_string()) {
+    if dump_tape {
+        env.dump_tape(&mut stderr(), None).expect("Failed to write to stderr");
+    }
+
+    let finished = env.finish();
+
+    if let Err(err) = result {
+        eprintln!("Runtime error: {}", err);
+        exit(exitcode::SOFTWARE);
+    }
+
+    if let Err(err) = finished {
+        eprintln!("Error while writing output: {}", err);
+        exit(exitcode::IOERR);
+    }
+}
+
+fn language(matches: &ArgMatches) -> Language {
+    match matches.value_of("language") {
+        Some(language_str) => match Language::make_from_string(language_str) {
             None => {
                 eprintln!("language must have exact 8 characters");
                 exit(exitcode::DATAERR);
@@ -73,22 +384,75 @@ fn main() {
             Some(language) => language
         },
         None => Language::default(),
+    }
+}
+
+fn read_error(filename: &str, err: std::io::Error) -> ! {
+    eprintln!("Error while reading {}: {}", filename, err);
+    exit(exitcode::NOINPUT);
+}
+
+fn compile(matches: &ArgMatches) {
+    let filename = matches.value_of("PROGRAM").unwrap();
+    let output = matches.value_of("output").unwrap();
+    let opt_level = matches.value_of("opt_level").unwrap().parse().expect("Optimization level");
+
+    let code = read_to_string(filename).unwrap_or_else(|err| read_error(filename, err));
+    let bytes = match compile_to_bytecode(&code, &language(matches), opt_level) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Parse error: {}", err);
+            exit(exitcode::DATAERR);
+        }
     };
 
-    let ops = parse(&code, &language);
+    if let Err(err) = write(output, bytes) {
+        eprintln!("Error while writing {}: {}", output, err);
+        exit(exitcode::CANTCREAT);
+    }
+}
+
+fn run_bytecode(matches: &ArgMatches) {
+    let filename = matches.value_of("BYTECODE").unwrap();
 
-    let mut data = vec![0u8; buffer_size];
+    let bytes = read(filename).unwrap_or_else(|err| read_error(filename, err));
+    let code = decode_or_exit(filename, &bytes);
+    run_compressed(&code, None, RunOptions::default(), false);
+}
 
+fn decode_or_exit(filename: &str, bytes: &[u8]) -> Code<CompressedOp> {
+    match decode_bytecode(bytes) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error while decoding {}: {}", filename, err);
+            exit(exitcode::DATAERR);
+        }
+    }
+}
+
+/// Run code as it is, such as decoded from bytecode, exiting on errors
+fn run_compressed(code: &Code<CompressedOp>, buffer_size: Option<usize>, options: RunOptions, dump_tape: bool) {
+    let tape_size = buffer_size.unwrap_or_else(|| suggested_tape_size(code, 1024 * 1024));
+    let mut data = vec![0u8; tape_size];
     let mut stdout = stdout();
     let mut stdin = stdin();
 
-    let mut env = Environment::new(&mut data, &mut stdin, &mut stdout);
+    let mut env = Environment::with_options(&mut data, &mut stdin, &mut stdout, options);
+    let result = run(code, &mut env);
 
-    if no_compress {
-        run(&ops, &mut env);
-    } else {
-        let compressed_ops = compress(&ops);
-        run(&compressed_ops, &mut env);
+    if dump_tape {
+        env.dump_tape(&mut stderr(), None).expect("Failed to write to stderr");
     }
 
+    let finished = env.finish();
+
+    if let Err(err) = result {
+        eprintln!("Runtime error: {}", err);
+        exit(exitcode::SOFTWARE);
+    }
+
+    if let Err(err) = finished {
+        eprintln!("Error while writing output: {}", err);
+        exit(exitcode::IOERR);
+    }
 }
@@ -0,0 +1,94 @@
+//! Tapes backed by memory-mapped files.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::path::Path;
+
+use memmap2::MmapMut;
+
+use crate::Tape;
+
+/// Fixed size tape of a writable mapping of a file, flushed to the file when
+/// the environment over it is finished or the tape is dropped.
+pub struct MmapTape(MmapMut);
+
+impl MmapTape {
+    /// Map a tape of `size` cells from the file at the path, creating the file if
+    /// needed and resizing it to `size` bytes. Cells keep the file's contents.
+    pub fn open<P: AsRef<Path>>(path: P, size: usize) -> io::Result<MmapTape> {
+        if size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "tape size must be positive"));
+        }
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        file.set_len(size as u64)?;
+
+        // The file must not be resized by others while it's mapped
+        let map = unsafe { MmapMut::map_mut(&file)? };
+        Ok(MmapTape(map))
+    }
+}
+
+impl Tape for MmapTape {
+    type Cell = u8;
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn get(&self, index: usize) -> u8 {
+        self.0[index]
+    }
+
+    fn set(&mut self, index: usize, value: u8) {
+        self.0[index] = value;
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for MmapTape {
+    fn drop(&mut self) {
+        let _ = self.0.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Cursor;
+    use crate::{parse, run, Environment, Language, RunOptions};
+
+    #[test]
+    fn test_mmap_tape() {
+        let path = std::env::temp_dir().join(format!("bfk-test-mmap-{}", std::process::id()));
+        let ops = parse("+>++>+++", &Language::default());
+
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut tape = MmapTape::open(&path, 16).unwrap();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        drop(env);
+        drop(tape);
+
+        let tape = fs::read(&path).unwrap();
+        assert_eq!(tape.len(), 16);
+        assert_eq!(&tape[0..4], [1, 2, 3, 0]);
+
+        // Runs again over the persisted cells
+        let mut tape = MmapTape::open(&path, 16).unwrap();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        env.finish().unwrap();
+
+        let tape = fs::read(&path).unwrap();
+        assert_eq!(&tape[0..4], [2, 4, 6, 0]);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
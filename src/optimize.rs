@@ -0,0 +1,776 @@
+//! Optimization passes, mostly over compressed code.
+//!
+//! Passes assume cells wrap on overflow and the pointer stays on the tape, so
+//! they may drop errors or clamping the original code would have hit under other
+//! `CellOverflow` and `PointerPolicy` settings.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::{Bracket, Code, CompressedOp, Op, Profile};
+
+/// Signature every pass over compressed code has, borrowing the code and
+/// returning optimized code so the original is kept.
+pub type Pass = fn(&Code<CompressedOp>) -> Code<CompressedOp>;
+
+/// What a pass did to the code. Program counters are into the code the pass ran over.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OptimizationEvent {
+    /// Run of `count` additions and subtractions, or pointer moves, merged into
+    /// their net effect, which may be nothing.
+    OppositesCancelled { pc: usize, count: usize },
+    /// Clearing loop `[-]` replaced with `SetZero`.
+    ClearLoopFolded { pc: usize },
+    /// Clear right after another clear of the same cell removed.
+    RepeatedClearRemoved { pc: usize },
+    /// `count` additions and subtractions removed as the cell is cleared right after them.
+    DeadStoresEliminated { pc: usize, count: usize },
+    /// Loop clearing the data it starts on at the end of its body replaced with `IfNonzero`.
+    IfLoopConverted { pc: usize },
+    /// `SetZero` followed by adding to the cell replaced with `SetValue`.
+    SetValueFolded { pc: usize },
+    /// Multiply loop run on known data replaced with setting the cells it adds to.
+    MultiplyLoopFolded { pc: usize },
+}
+
+impl fmt::Display for OptimizationEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptimizationEvent::OppositesCancelled { pc, count } =>
+                write!(f, "pc {}: merged {} opposite operations", pc, count),
+            OptimizationEvent::ClearLoopFolded { pc } => write!(f, "pc {}: folded clear loop", pc),
+            OptimizationEvent::RepeatedClearRemoved { pc } => write!(f, "pc {}: removed repeated clear", pc),
+            OptimizationEvent::DeadStoresEliminated { pc, count } =>
+                write!(f, "pc {}: eliminated {} dead stores", pc, count),
+            OptimizationEvent::IfLoopConverted { pc } => write!(f, "pc {}: converted loop to if", pc),
+            OptimizationEvent::SetValueFolded { pc } => write!(f, "pc {}: folded set value", pc),
+            OptimizationEvent::MultiplyLoopFolded { pc } => write!(f, "pc {}: folded multiply loop", pc),
+        }
+    }
+}
+
+/// Make code from the ops, each paired with the pc in `code` it came from for its
+/// source offset. The ops must have balanced brackets.
+fn rebuild<O: Bracket>(code: &Code<O>, ops: Vec<(O, usize)>) -> Code<O> {
+    let mut jump_table = vec![0; ops.len()];
+    let mut map_stack = Vec::new();
+    for (pc, (op, _)) in ops.iter().enumerate() {
+        if op.is_loop_start() {
+            map_stack.push(pc);
+        } else if op.is_loop_end() {
+            let begin = map_stack.pop().expect("Unmatched loop end");
+            jump_table[begin] = pc + 1;
+            jump_table[pc] = begin + 1;
+        }
+    }
+
+    let source_map = code.source_map.as_ref().map(|map| ops.iter().map(|&(_, origin)| map[origin]).collect());
+    Code { ops: ops.into_iter().map(|(op, _)| op).collect(), jump_table, source_map }
+}
+
+/// Whether a clearing loop `[-]` starts at the pc
+fn is_clear_loop(ops: &[CompressedOp], pc: usize) -> bool {
+    matches!(ops.get(pc..pc + 3), Some([CompressedOp::LoopStart, CompressedOp::Sub(1), CompressedOp::LoopEnd]))
+}
+
+/// Replace clearing loops `[-]` with `SetZero` in uncompressed code
+pub fn fold_plain_clear_loops(code: &Code<Op>) -> Code<Op> {
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut pc = 0;
+
+    while pc < code.ops.len() {
+        if let Some([Op::LoopStart, Op::Dec, Op::LoopEnd]) = code.ops.get(pc..pc + 3) {
+            ops.push((Op::SetZero, pc));
+            pc += 3;
+        } else {
+            ops.push((code.ops[pc], pc));
+            pc += 1;
+        }
+    }
+
+    rebuild(code, ops)
+}
+
+/// Merge runs of additions and subtractions, and runs of pointer moves, into
+/// their net effect
+pub fn cancel_opposites(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    cancel_opposites_with_events(code, &mut Vec::new())
+}
+
+fn cancel_opposites_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    fn data_delta(op: CompressedOp) -> Option<i64> {
+        match op {
+            CompressedOp::Add(n) => Some(n as i64),
+            CompressedOp::Sub(n) => Some(-(n as i64)),
+            _ => None,
+        }
+    }
+    fn pointer_delta(op: CompressedOp) -> Option<i128> {
+        match op {
+            CompressedOp::Forward(n) => Some(n as i128),
+            CompressedOp::Back(n) => Some(-(n as i128)),
+            _ => None,
+        }
+    }
+
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut pc = 0;
+
+    while pc < code.ops.len() {
+        let start = pc;
+        let op = code.ops[pc];
+
+        let merged = if data_delta(op).is_some() {
+            let mut net = 0i64;
+            while let Some(delta) = code.ops.get(pc).and_then(|&op| data_delta(op)) {
+                net += delta;
+                pc += 1;
+            }
+            match net {
+                0 => None,
+                n if n > 0 => Some(CompressedOp::Add(n as u32)),
+                n => Some(CompressedOp::Sub(n.unsigned_abs() as u32)),
+            }
+        } else if pointer_delta(op).is_some() {
+            let mut net = 0i128;
+            while let Some(delta) = code.ops.get(pc).and_then(|&op| pointer_delta(op)) {
+                net += delta;
+                pc += 1;
+            }
+            match net {
+                0 => None,
+                n if n > 0 => Some(CompressedOp::Forward(n as usize)),
+                n => Some(CompressedOp::Back(n.unsigned_abs() as usize)),
+            }
+        } else {
+            ops.push((op, pc));
+            pc += 1;
+            continue;
+        };
+
+        if pc - start > 1 || merged.is_none() {
+            events.push(OptimizationEvent::OppositesCancelled { pc: start, count: pc - start });
+        }
+        if let Some(op) = merged {
+            ops.push((op, start));
+        }
+    }
+
+    rebuild(code, ops)
+}
+
+/// Replace clearing loops `[-]` with `SetZero`, dropping clears of a cell just cleared
+pub fn fold_clear_loops(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    fold_clear_loops_with_events(code, &mut Vec::new())
+}
+
+fn fold_clear_loops_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut pc = 0;
+
+    while pc < code.ops.len() {
+        let len = if is_clear_loop(&code.ops, pc) { 3 } else if code.ops[pc] == CompressedOp::SetZero { 1 } else { 0 };
+        if len > 0 && matches!(ops.last(), Some((CompressedOp::SetZero, _))) {
+            events.push(OptimizationEvent::RepeatedClearRemoved { pc });
+            pc += len;
+        } else if len == 3 {
+            events.push(OptimizationEvent::ClearLoopFolded { pc });
+            ops.push((CompressedOp::SetZero, pc));
+            pc += 3;
+        } else {
+            ops.push((code.ops[pc], pc));
+            pc += 1;
+        }
+    }
+
+    rebuild(code, ops)
+}
+
+/// Remove additions and subtractions to a cell which is cleared by `[-]` or
+/// `SetZero` right after them, before anything reads it or the pointer moves.
+pub fn eliminate_dead_stores(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    eliminate_dead_stores_with_events(code, &mut Vec::new())
+}
+
+fn eliminate_dead_stores_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    let mut ops: Vec<(CompressedOp, usize)> = Vec::with_capacity(code.ops.len());
+    // Number of ops at the end of `ops` only adding to or subtracting from the current cell
+    let mut pending = 0;
+
+    for (pc, &op) in code.ops.iter().enumerate() {
+        match op {
+            CompressedOp::Add(_) | CompressedOp::Sub(_) => pending += 1,
+            _ if op == CompressedOp::SetZero || is_clear_loop(&code.ops, pc) => {
+                if pending > 0 {
+                    events.push(OptimizationEvent::DeadStoresEliminated { pc: pc - pending, count: pending });
+                }
+                ops.truncate(ops.len() - pending);
+                pending = 0;
+            }
+            _ => pending = 0,
+        }
+        ops.push((op, pc));
+    }
+
+    rebuild(code, ops)
+}
+
+/// Replace `SetZero` followed by `Add(n)` with `SetValue(n)`
+pub fn fold_set_values(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    fold_set_values_with_events(code, &mut Vec::new())
+}
+
+fn fold_set_values_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut pc = 0;
+
+    while pc < code.ops.len() {
+        match code.ops[pc..] {
+            [CompressedOp::SetZero, CompressedOp::Add(n), ..] => {
+                events.push(OptimizationEvent::SetValueFolded { pc });
+                ops.push((CompressedOp::SetValue(n), pc));
+                pc += 2;
+            }
+            _ => {
+                ops.push((code.ops[pc], pc));
+                pc += 1;
+            }
+        }
+    }
+
+    rebuild(code, ops)
+}
+
+/// Whether the body of the loop from `start` to `end` runs at most once: it must
+/// end by clearing the data the loop starts on, and may hold no loops but clearing
+/// loops so it has only one path to get there.
+fn is_if_loop(ops: &[CompressedOp], start: usize, end: usize) -> bool {
+    let body_end = if ops[end - 1] == CompressedOp::SetZero {
+        end - 1
+    } else if end >= start + 4 && is_clear_loop(ops, end - 3) {
+        end - 3
+    } else {
+        return false;
+    };
+
+    let mut offset = 0i128;
+    let mut pc = start + 1;
+    while pc < body_end {
+        match ops[pc] {
+            CompressedOp::Forward(n) => offset += n as i128,
+            CompressedOp::Back(n) => offset -= n as i128,
+            _ if is_clear_loop(ops, pc) => pc += 2,
+            op if op.is_loop_start() || op.is_loop_end() => return false,
+            _ => (),
+        }
+        pc += 1;
+    }
+    offset == 0
+}
+
+/// Replace loops whose body clears the data they start on at its end, such as
+/// `[>+<[-]]`, with `IfNonzero` bodies which don't jump back
+pub fn convert_if_loops(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    convert_if_loops_with_events(code, &mut Vec::new())
+}
+
+fn convert_if_loops_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    let mut ops: Vec<(CompressedOp, usize)> = code.ops.iter().copied().zip(0..).collect();
+
+    for (pc, &op) in code.ops.iter().enumerate() {
+        let end = code.jump_table[pc].wrapping_sub(1);
+        if op == CompressedOp::LoopStart && is_if_loop(&code.ops, pc, end) {
+            events.push(OptimizationEvent::IfLoopConverted { pc });
+            ops[pc].0 = CompressedOp::IfNonzero;
+            ops[end].0 = CompressedOp::EndIf;
+        }
+    }
+
+    rebuild(code, ops)
+}
+
+/// Largest magnitude of data the folder computes, so its additions and
+/// subtractions mean the same for cells of any width
+const MAX_FOLDED: i64 = u8::MAX as i64;
+
+/// Pointer offsets and how much a counted multiply loop like `[>+++<-]` starting
+/// at the pc adds to them per iteration. Its body may only add, subtract and move
+/// the pointer, return to where it started and subtract 1 there.
+fn multiply_loop(code: &Code<CompressedOp>, start: usize) -> Option<(Vec<(i64, i64)>, usize)> {
+    if code.ops[start] != CompressedOp::LoopStart {
+        return None;
+    }
+    let end = code.jump_table[start] - 1;
+
+    let mut offset = 0i64;
+    let mut deltas = BTreeMap::new();
+    for &op in &code.ops[start + 1..end] {
+        match op {
+            CompressedOp::Add(n) => *deltas.entry(offset).or_insert(0) += n as i64,
+            CompressedOp::Sub(n) => *deltas.entry(offset).or_insert(0) -= n as i64,
+            CompressedOp::Forward(n) => offset = offset.checked_add(i64::try_from(n).ok()?)?,
+            CompressedOp::Back(n) => offset = offset.checked_sub(i64::try_from(n).ok()?)?,
+            _ => return None,
+        }
+    }
+
+    if offset != 0 || deltas.remove(&0) != Some(-1) {
+        return None;
+    }
+    Some((deltas.into_iter().filter(|&(_, delta)| delta != 0).collect(), end))
+}
+
+/// Fold the start of the code up to the first operation depending on input or
+/// on data not known statically, replacing counted multiply loops like
+/// `++++[>+++<-]` with setting the cells they add to (`>++++++++++++<`).
+///
+/// Unlike the other passes this assumes the code runs from the start of a fresh
+/// tape, so it isn't part of `optimize_with_events`. It also assumes the pointer
+/// never reaches the right end of the tape, while folding stops before any move
+/// left of the starting cell, as the pointer would be clamped or fail there.
+pub fn fold_multiply_loops(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    fold_multiply_loops_with_events(code, &mut Vec::new())
+}
+
+fn fold_multiply_loops_with_events(code: &Code<CompressedOp>, events: &mut Vec<OptimizationEvent>) -> Code<CompressedOp> {
+    // Data of every cell changed so far by its offset from the start, and the pointer offset
+    let mut cells: BTreeMap<i64, i64> = BTreeMap::new();
+    let mut offset = 0i64;
+    let mut folded = Vec::new();
+    let mut pc = 0;
+
+    fn in_range(value: i64) -> Option<i64> {
+        Some(value).filter(|value| value.abs() <= MAX_FOLDED)
+    }
+
+    while pc < code.ops.len() {
+        let data = cells.get(&offset).copied().unwrap_or(0);
+        let next = match code.ops[pc] {
+            CompressedOp::Add(n) => in_range(data + n as i64).map(|value| { cells.insert(offset, value); pc + 1 }),
+            CompressedOp::Sub(n) => in_range(data - n as i64).map(|value| { cells.insert(offset, value); pc + 1 }),
+            CompressedOp::SetZero => { cells.insert(offset, 0); Some(pc + 1) }
+            CompressedOp::SetValue(n) => in_range(n as i64).map(|value| { cells.insert(offset, value); pc + 1 }),
+            CompressedOp::Forward(n) => i64::try_from(n).ok().and_then(|n| offset.checked_add(n)).map(|new| { offset = new; pc + 1 }),
+            CompressedOp::Back(n) => i64::try_from(n).ok().and_then(|n| offset.checked_sub(n)).filter(|&new| new >= 0).map(|new| { offset = new; pc + 1 }),
+            // A negative count iterates a number of times depending on the cell width
+            CompressedOp::LoopStart if data >= 0 => multiply_loop(code, pc).and_then(|(deltas, end)| {
+                let values = deltas.iter()
+                    .map(|&(delta_offset, delta)| {
+                        let target = offset.checked_add(delta_offset).filter(|&target| target >= 0)?;
+                        in_range(cells.get(&target).copied().unwrap_or(0) + delta * data).map(|value| (target, value))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                cells.extend(values);
+                cells.insert(offset, 0);
+                folded.push(pc);
+                Some(end + 1)
+            }),
+            _ => None,
+        };
+        match next {
+            Some(next) => pc = next,
+            None => break,
+        }
+    }
+
+    if folded.is_empty() {
+        return code.clone();
+    }
+    events.extend(folded.into_iter().map(|pc| OptimizationEvent::MultiplyLoopFolded { pc }));
+
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut position = 0i64;
+    let mut move_to = |ops: &mut Vec<(CompressedOp, usize)>, target: i64| {
+        match target - position {
+            0 => (),
+            n if n > 0 => ops.push((CompressedOp::Forward(n as usize), 0)),
+            n => ops.push((CompressedOp::Back(n.unsigned_abs() as usize), 0)),
+        }
+        position = target;
+    };
+    for (&target, &value) in cells.iter().filter(|&(_, &value)| value != 0) {
+        move_to(&mut ops, target);
+        if value > 0 {
+            ops.push((CompressedOp::Add(value as u32), 0));
+        } else {
+            ops.push((CompressedOp::Sub(value.unsigned_abs() as u32), 0));
+        }
+    }
+    move_to(&mut ops, offset);
+    ops.extend(code.ops[pc..].iter().copied().zip(pc..));
+
+    rebuild(code, ops)
+}
+
+/// Run every pass in turn, returning what each did
+pub fn optimize_with_events(code: &Code<CompressedOp>) -> (Code<CompressedOp>, Vec<OptimizationEvent>) {
+    let mut events = Vec::new();
+    let code = cancel_opposites_with_events(code, &mut events);
+    let code = fold_clear_loops_with_events(&code, &mut events);
+    let code = eliminate_dead_stores_with_events(&code, &mut events);
+    let code = convert_if_loops_with_events(&code, &mut events);
+    let code = fold_set_values_with_events(&code, &mut events);
+    (code, events)
+}
+
+/// Most rounds of passes `optimize` runs, in case passes keep rewriting each other's output
+const MAX_OPTIMIZE_ROUNDS: usize = 16;
+
+/// Run every pass of `optimize_with_events` again until the code stops changing
+pub fn optimize(code: &Code<CompressedOp>) -> Code<CompressedOp> {
+    let mut code = code.clone();
+    for _ in 0..MAX_OPTIMIZE_ROUNDS {
+        let (optimized, _) = optimize_with_events(&code);
+        if optimized.ops == code.ops {
+            break;
+        }
+        code = optimized;
+    }
+    code
+}
+
+/// Optimize only the loops whose body ran more times than the threshold in the
+/// profile of the code, as `optimize` does, leaving the rest as it is.
+///
+/// This saves optimizing cold code, but hot loops get no passes beyond those of
+/// `optimize`: `fold_multiply_loops` only holds from the start of a fresh tape,
+/// and there is no operation to rewrite a multiply loop in the middle of the code to.
+pub fn optimize_hot(code: &Code<CompressedOp>, profile: &Profile, threshold: u64) -> Code<CompressedOp> {
+    let hot = profile.hot_loops(code, threshold);
+    let mut ops = Vec::with_capacity(code.ops.len());
+    let mut pc = 0;
+
+    // Loops come ordered by their start, so nested hot loops are skipped along with their outermost hot loop
+    for span in code.loops().into_iter().filter(|span| hot.contains(&span.start)) {
+        if span.start < pc {
+            continue;
+        }
+        ops.extend(code.ops[pc..span.start].iter().copied().zip(pc..));
+
+        // Optimize the loop on its own, tracking the pc each op came from in its source map
+        // Entries of ops other than brackets are unused, and saturate at zero
+        let region = Code {
+            ops: code.ops[span.start..=span.end].to_vec(),
+            jump_table: code.jump_table[span.start..=span.end].iter().map(|&jump| jump.saturating_sub(span.start)).collect(),
+            source_map: Some((span.start..=span.end).collect()),
+        };
+        let optimized = optimize(&region);
+        ops.extend(optimized.ops.into_iter().zip(optimized.source_map.expect("Passes keep source maps")));
+        pc = span.end + 1;
+    }
+    ops.extend(code.ops[pc..].iter().copied().zip(pc..));
+
+    rebuild(code, ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, expand_rle, parse, parse_with_source_map, run, run_profiled, Environment, Language, HELLO_BF};
+
+    #[test]
+    fn test_eliminate_dead_stores() {
+        let language = Language::default();
+
+        let code = eliminate_dead_stores(&compress(&parse_with_source_map("+++[-]", &language)));
+        assert_eq!(code.ops, vec![CompressedOp::LoopStart, CompressedOp::Sub(1), CompressedOp::LoopEnd]);
+        assert_eq!(code.source_map(), Some(&[3, 4, 5][..]));
+        assert!(code.validate().is_ok());
+
+        let code = eliminate_dead_stores(&compress(&parse("+[->+<]+++--[-]>", &language)));
+        assert_eq!(code.ops, compress(&parse("+[->+<][-]>", &language)).ops);
+        assert!(code.validate().is_ok());
+    }
+
+    #[test]
+    fn test_eliminate_dead_stores_keeps_reads() {
+        let language = Language::default();
+
+        for source in ["+.[-]", "+>[-]", "+[-+]", "+[[-]]", ",[-]", "++[--]"] {
+            let compressed = compress(&parse(source, &language));
+            assert_eq!(eliminate_dead_stores(&compressed).ops, compressed.ops, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_fold_plain_clear_loops() {
+        let language = Language::default();
+
+        let code = fold_plain_clear_loops(&parse_with_source_map("[-]", &language));
+        assert_eq!(code.ops, [Op::SetZero]);
+
+        let code = fold_plain_clear_loops(&parse_with_source_map("+[>[-]+<[-]]>.", &language));
+        assert_eq!(code.ops, [
+            Op::Inc, Op::LoopStart, Op::IncPtr, Op::SetZero, Op::Inc, Op::DecPtr, Op::SetZero, Op::LoopEnd,
+            Op::IncPtr, Op::PutChar,
+        ]);
+        assert_eq!(code.source_map(), Some(&[0, 1, 2, 3, 6, 7, 8, 11, 12, 13][..]));
+        assert!(code.validate().is_ok());
+        assert_eq!(compress(&code).ops[3], CompressedOp::SetZero);
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(output, [1]);
+
+        let code = parse("[+][--]", &language);
+        assert_eq!(fold_plain_clear_loops(&code), code);
+    }
+
+    #[test]
+    fn test_cancel_opposites() {
+        let language = Language::default();
+
+        let code = cancel_opposites(&compress(&parse_with_source_map("+++--[>><<<-]>-+", &language)));
+        assert_eq!(code.ops, [
+            CompressedOp::Add(1),
+            CompressedOp::LoopStart,
+            CompressedOp::Back(1),
+            CompressedOp::Sub(1),
+            CompressedOp::LoopEnd,
+            CompressedOp::Forward(1),
+        ]);
+        assert_eq!(code.source_map(), Some(&[0, 5, 6, 11, 12, 13][..]));
+        assert!(code.validate().is_ok());
+    }
+
+    #[test]
+    fn test_convert_if_loops() {
+        let language = Language::default();
+
+        let code = convert_if_loops(&compress(&parse_with_source_map("[>+<[-]]", &language)));
+        assert_eq!(code.ops, [
+            CompressedOp::IfNonzero,
+            CompressedOp::Forward(1),
+            CompressedOp::Add(1),
+            CompressedOp::Back(1),
+            CompressedOp::LoopStart,
+            CompressedOp::Sub(1),
+            CompressedOp::LoopEnd,
+            CompressedOp::EndIf,
+        ]);
+        assert_eq!(code.source_map(), Some(&[0, 1, 2, 3, 4, 5, 6, 7][..]));
+        assert!(code.validate().is_ok());
+
+        for (mut data, expected) in [([3, 0], [0, 1]), ([0, 5], [0, 5])] {
+            let mut input = Cursor::new(vec![]);
+            let mut output = Vec::new();
+            let mut env = Environment::new(&mut data, &mut input, &mut output);
+            run(&code, &mut env).unwrap();
+            assert_eq!(data, expected);
+        }
+
+        let code = convert_if_loops(&fold_clear_loops(&compress(&parse("+[<[-]>.[-]]", &language))));
+        assert_eq!(code.ops[1], CompressedOp::IfNonzero);
+        assert_eq!(code.ops.last(), Some(&CompressedOp::EndIf));
+
+        for source in ["[>+<-]", "[>+[-]]", "[>[<]<[-]]", "[[-]>]", "[-]", "[<[-]>-]"] {
+            let compressed = compress(&parse(source, &language));
+            assert_eq!(convert_if_loops(&compressed).ops, compressed.ops, "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_fold_multiply_loops() {
+        let language = Language::default();
+
+        let code = fold_multiply_loops(&compress(&parse_with_source_map("++++[>+++<-]", &language)));
+        assert_eq!(code.ops, [CompressedOp::Forward(1), CompressedOp::Add(12), CompressedOp::Back(1)]);
+        assert!(code.validate().is_ok());
+
+        let source = "+++++[>++>---<<-]>>>+<<[.]++[>>+<<-]>.,[>+<-]";
+        let folded = fold_multiply_loops(&compress(&parse_with_source_map(source, &language)));
+        assert_eq!(folded.ops[..6], [
+            CompressedOp::Forward(1),
+            CompressedOp::Add(10),
+            CompressedOp::Forward(1),
+            CompressedOp::Sub(15),
+            CompressedOp::Forward(1),
+            CompressedOp::Add(1),
+        ]);
+        assert_eq!(folded.ops[6..10], [CompressedOp::Back(2), CompressedOp::LoopStart, CompressedOp::PutChar, CompressedOp::LoopEnd]);
+        assert_eq!(folded.source_map().map(|map| map[7]), Some(23));
+        assert!(folded.validate().is_ok());
+
+        let mut events = Vec::new();
+        fold_multiply_loops_with_events(&compress(&parse(source, &language)), &mut events);
+        assert_eq!(events, [OptimizationEvent::MultiplyLoopFolded { pc: 1 }]);
+
+        // Loops skipped on the fresh tape fold to nothing
+        assert_eq!(fold_multiply_loops(&compress(&parse("[>+<-]", &language))).ops, []);
+
+        for source in [",[>+<-]", "-[>+<-]", "++[>+<--]", "++[>+>-]", "++[>.<-]", "16+[>16+<-]", "+>"] {
+            let compressed = compress(&parse(&expand_rle(source, &language).unwrap(), &language));
+            assert_eq!(fold_multiply_loops(&compressed).ops, compressed.ops, "{}", source);
+        }
+
+        // Moves left of the starting cell are clamped, so they aren't folded past
+        for (source, expected) in [("+++[<++>-]<.", vec![3]), ("<<>+++[>++<-]<<.>.>.>.", vec![0, 0, 6, 0])] {
+            let compressed = compress(&parse(source, &language));
+            for code in [&compressed, &fold_multiply_loops(&compressed)] {
+                let mut data = [0; 16];
+                let mut input = Cursor::new(vec![]);
+                let mut output = Vec::new();
+                run(code, &mut Environment::new(&mut data, &mut input, &mut output)).unwrap();
+                assert_eq!(output, expected, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimize_hot() {
+        let language = Language::default();
+        let code = compress(&parse_with_source_map("+[-]+++++++[-]>+<[->[-]++++<]+-", &language));
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let profile = run_profiled(&code, &mut env).unwrap();
+
+        // Only the loop clearing 7 runs more than twice, the last loop doesn't run at all
+        let optimized = optimize_hot(&code, &profile, 2);
+        assert_eq!(optimized.ops, [
+            CompressedOp::Add(1),
+            CompressedOp::LoopStart,
+            CompressedOp::Sub(1),
+            CompressedOp::LoopEnd,
+            CompressedOp::Add(7),
+            CompressedOp::SetZero,
+            CompressedOp::Forward(1),
+            CompressedOp::Add(1),
+            CompressedOp::Back(1),
+            CompressedOp::LoopStart,
+            CompressedOp::Sub(1),
+            CompressedOp::Forward(1),
+            CompressedOp::LoopStart,
+            CompressedOp::Sub(1),
+            CompressedOp::LoopEnd,
+            CompressedOp::Add(4),
+            CompressedOp::Back(1),
+            CompressedOp::LoopEnd,
+            CompressedOp::Add(1),
+            CompressedOp::Sub(1),
+        ]);
+        assert!(optimized.validate().is_ok());
+        assert_eq!(optimized.source_map().map(|map| map[5]), Some(11));
+        assert_eq!(optimized.source_map().map(|map| map[18]), Some(29));
+
+        assert_eq!(optimize_hot(&code, &profile, 7), code);
+
+        // Nested hot loops are optimized along with the outermost one
+        let code = compress(&parse("++[>+++[-]<-]", &language));
+        let mut data = [0; 2];
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let profile = run_profiled(&code, &mut env).unwrap();
+        assert_eq!(profile.hot_loops(&code, 0), [1, 4]);
+
+        let optimized = optimize_hot(&code, &profile, 3);
+        assert_eq!(optimized.ops[3..5], [CompressedOp::Add(3), CompressedOp::SetZero]);
+        let optimized = optimize_hot(&code, &profile, 0);
+        assert_eq!(optimized.ops, [
+            CompressedOp::Add(2),
+            CompressedOp::LoopStart,
+            CompressedOp::Forward(1),
+            CompressedOp::SetZero,
+            CompressedOp::Back(1),
+            CompressedOp::Sub(1),
+            CompressedOp::LoopEnd,
+        ]);
+        assert!(optimized.validate().is_ok());
+    }
+
+    #[test]
+    fn test_fold_set_values() {
+        let language = Language::default();
+
+        let (code, events) = optimize_with_events(&compress(&parse_with_source_map("[-]+++", &language)));
+        assert_eq!(code.ops, [CompressedOp::SetValue(3)]);
+        assert_eq!(code.source_map(), Some(&[0][..]));
+        assert_eq!(events, [OptimizationEvent::ClearLoopFolded { pc: 0 }, OptimizationEvent::SetValueFolded { pc: 0 }]);
+
+        let mut data = [7; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(data, [3]);
+
+        // Only adding right after clearing the same cell folds
+        let code = fold_clear_loops(&compress(&parse("[-]>++[-]-", &language)));
+        assert_eq!(fold_set_values(&code), code);
+    }
+
+    #[test]
+    fn test_optimize_with_events() {
+        let language = Language::default();
+
+        let (code, events) = optimize_with_events(&compress(&parse("[-]", &language)));
+        assert_eq!(code.ops, [CompressedOp::SetZero]);
+        assert_eq!(events, [OptimizationEvent::ClearLoopFolded { pc: 0 }]);
+
+        let (code, events) = optimize_with_events(&compress(&parse_with_source_map("[-][-]", &language)));
+        assert_eq!(code.ops, [CompressedOp::SetZero]);
+        assert_eq!(code.source_map(), Some(&[0][..]));
+        assert_eq!(events, [OptimizationEvent::ClearLoopFolded { pc: 0 }, OptimizationEvent::RepeatedClearRemoved { pc: 3 }]);
+        assert_eq!(fold_clear_loops(&compress(&parse("[-]>[-]", &language))).ops, [
+            CompressedOp::SetZero, CompressedOp::Forward(1), CompressedOp::SetZero,
+        ]);
+
+        let mut data = [7; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        assert_eq!(data, [0]);
+
+        let (code, events) = optimize_with_events(&compress(&parse("+-.++[-]", &language)));
+        assert_eq!(code.ops, [CompressedOp::PutChar, CompressedOp::SetZero]);
+        assert!(code.validate().is_ok());
+        assert_eq!(events, [
+            OptimizationEvent::OppositesCancelled { pc: 0, count: 2 },
+            OptimizationEvent::ClearLoopFolded { pc: 2 },
+            OptimizationEvent::DeadStoresEliminated { pc: 1, count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn test_optimize() {
+        let language = Language::default();
+
+        fn output(code: &Code<CompressedOp>) -> Vec<u8> {
+            let mut data = [0; 16];
+            let mut input = Cursor::new(vec![]);
+            let mut output = Vec::new();
+            let mut env = Environment::new(&mut data, &mut input, &mut output);
+            run(code, &mut env).unwrap();
+            output
+        }
+
+        // Hello World itself has nothing to optimize, so clear some cells after it
+        let code = compress(&parse(&format!("{}<[-]>[-]++[-]+-", HELLO_BF), &language));
+        let optimized = optimize(&code);
+        assert_eq!(output(&optimized), b"Hello World!\n");
+        assert!(optimized.ops.len() < code.ops.len());
+        assert!(optimized.validate().is_ok());
+        assert_eq!(optimize(&optimized).ops, optimized.ops);
+    }
+
+    #[test]
+    fn test_passes_keep_input() {
+        let passes: [Pass; 8] = [
+            cancel_opposites, fold_clear_loops, eliminate_dead_stores, convert_if_loops,
+            fold_set_values, fold_multiply_loops, optimize, |code| optimize_with_events(code).0,
+        ];
+        let code = compress(&parse_with_source_map("+-++[-]+>++++[<+++>-]<[.[-]]>>+<<", &Language::default()));
+        let original = code.clone();
+        for pass in passes.iter() {
+            pass(&code);
+            assert_eq!(code, original);
+        }
+    }
+}
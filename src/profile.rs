@@ -0,0 +1,113 @@
+//! Counting how often operations run.
+
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+
+use crate::{run_with_hook, Bracket, Code, Environment, Runnable, RuntimeError, Tape};
+
+/// Number of times each operation ran.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Profile {
+    counts: Vec<u64>,
+}
+
+/// How much a loop ran.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct LoopProfile {
+    /// Program counter of the loop start
+    pub start_pc: usize,
+    /// Number of times the body ran
+    pub iterations: u64,
+    /// Number of operations run inside the body, including nested loops
+    pub inner_ops: u64,
+}
+
+impl Profile {
+    /// Number of times the operation at each pc ran
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// Number of times the operation at the pc ran
+    pub fn count(&self, pc: usize) -> u64 {
+        self.counts.get(pc).copied().unwrap_or(0)
+    }
+
+    /// How much each loop of the profiled code ran, ordered by its start
+    pub fn loops<O: Bracket>(&self, code: &Code<O>) -> Vec<LoopProfile> {
+        code.loops()
+            .into_iter()
+            .map(|span| LoopProfile {
+                start_pc: span.start,
+                // Each iteration enters the body right after the loop start, which is the loop end for an empty body
+                iterations: self.count(span.start + 1),
+                inner_ops: (span.start + 1..span.end).map(|pc| self.count(pc)).sum(),
+            })
+            .collect()
+    }
+
+    /// Start pcs of the loops of the profiled code whose body ran more times than the threshold
+    pub fn hot_loops<O: Bracket>(&self, code: &Code<O>, threshold: u64) -> Vec<usize> {
+        self.loops(code)
+            .into_iter()
+            .filter(|profile| profile.iterations > threshold)
+            .map(|profile| profile.start_pc)
+            .collect()
+    }
+}
+
+/// Execute operations, counting how many times each one ran
+pub fn run_profiled<R, W, T, O>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<Profile, RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable
+{
+    let mut counts = vec![0; code.ops.len()];
+    run_with_hook(code, env, |_, env| {
+        counts[env.pc()] += 1;
+        ControlFlow::Continue(())
+    })?;
+    Ok(Profile { counts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, Language};
+
+    #[test]
+    fn test_loop_profile() {
+        let code = compress(&parse("+++++[->++[->+<]<]>>.<[]", &Language::default()));
+
+        let mut data = [0; 3];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let profile = run_profiled(&code, &mut env).unwrap();
+        assert_eq!(profile.counts().len(), code.ops.len());
+        assert_eq!(profile.count(0), 1);
+
+        let loops = profile.loops(&code);
+        assert_eq!(loops.len(), 3);
+        assert_eq!(loops[0], LoopProfile { start_pc: 1, iterations: 5, inner_ops: 5 * 5 + 5 * 2 * 5 });
+        assert_eq!(loops[1], LoopProfile { start_pc: 5, iterations: 10, inner_ops: 10 * 4 });
+        assert_eq!(loops[2], LoopProfile { start_pc: 16, iterations: 0, inner_ops: 0 });
+        assert_eq!(output, [10]);
+    }
+
+    #[test]
+    fn test_hot_loops() {
+        let code = compress(&parse("++[>++++++++++[>+<-]<-]>>.", &Language::default()));
+
+        let mut data = [0; 3];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let profile = run_profiled(&code, &mut env).unwrap();
+        assert_eq!(profile.hot_loops(&code, 5), [4]);
+        assert_eq!(profile.hot_loops(&code, 1), [1, 4]);
+        assert_eq!(profile.hot_loops(&code, 20), Vec::<usize>::new());
+        assert_eq!(output, [20]);
+    }
+}
@@ -0,0 +1,70 @@
+//! Reproducible pseudo-random input.
+
+use std::io::{self, Read};
+
+/// Reader giving `len` pseudo-random bytes then EOF. The bytes depend only on the
+/// seed, so runs reading from readers with the same seed and length are the same.
+#[derive(Debug, Clone)]
+pub struct RandomInput {
+    state: u64,
+    remaining: u64,
+}
+
+impl RandomInput {
+    /// Reader of `len` bytes generated from the seed
+    pub fn new(seed: u64, len: u64) -> Self {
+        RandomInput { state: seed, remaining: len }
+    }
+
+    /// Next value of SplitMix64, which is fine for any seed including zero
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Read for RandomInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        for chunk in buf[..len].chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, parse, run, Environment, Language};
+
+    fn run_cat(seed: u64) -> Vec<u8> {
+        let code = compress(&parse(",[.,]", &Language::default()));
+
+        let mut data = [0; 1];
+        let mut input = RandomInput::new(seed, 64);
+        let mut output = Vec::new();
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        run(&code, &mut env).unwrap();
+        output
+    }
+
+    #[test]
+    fn test_random_input() {
+        assert_eq!(run_cat(42), run_cat(42));
+        assert_ne!(run_cat(42), run_cat(43));
+
+        let mut bytes = Vec::new();
+        RandomInput::new(0, 20).read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 20);
+        assert!(bytes.iter().any(|&byte| byte != 0));
+
+        let mut bytes = Vec::new();
+        RandomInput::new(7, 0).read_to_end(&mut bytes).unwrap();
+        assert!(bytes.is_empty());
+    }
+}
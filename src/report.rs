@@ -0,0 +1,113 @@
+//! Running code within limits and reporting how the run ended.
+
+use std::io::{Read, Write};
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+
+use crate::{run_with_hook, Code, Environment, Runnable, RuntimeError, Tape};
+
+/// Steps run between checks of the timeout, so the clock isn't read every step
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// Bounds a reported run stops at, besides any in the options of its environment.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RunLimits {
+    /// Most operations to run, if any
+    pub max_steps: Option<u64>,
+    /// Longest wall time to run for, checked every so many steps, if any
+    pub timeout: Option<Duration>,
+}
+
+/// How a reported run ended.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RunOutcome {
+    /// Ran to the end of the code.
+    Halted,
+    /// Stopped at the step limit of the limits or the options.
+    StepLimit,
+    /// Stopped at the timeout.
+    Timeout,
+    /// Failed with an error other than exceeding the step limit.
+    Error(RuntimeError),
+}
+
+/// How a reported run ended, with what it did.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct RunReport {
+    /// How the run ended
+    pub outcome: RunOutcome,
+    /// Operations run
+    pub steps: u64,
+    /// Bytes of output written
+    pub bytes_written: u64,
+    /// Wall time running took
+    pub elapsed: Duration,
+}
+
+/// Execute operations until the code ends, fails or reaches one of the limits,
+/// reporting which happened
+pub fn run_report<R, W, T, O>(code: &Code<O>, env: &mut Environment<R, W, T>, limits: RunLimits) -> RunReport
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable
+{
+    let start = Instant::now();
+    let steps_before = env.steps();
+    let bytes_before = env.bytes_written();
+    let mut stopped = None;
+
+    let result = run_with_hook(code, env, |_, env| {
+        let steps = env.steps() - steps_before;
+        if limits.max_steps.is_some_and(|max| steps >= max) {
+            stopped = Some(RunOutcome::StepLimit);
+        } else if steps.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && limits.timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+            stopped = Some(RunOutcome::Timeout);
+        }
+        if stopped.is_some() { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+    });
+
+    let outcome = match (result, stopped) {
+        (Err(RuntimeError::StepLimitExceeded { .. }), _) => RunOutcome::StepLimit,
+        (Err(err), _) => RunOutcome::Error(err),
+        (Ok(()), Some(outcome)) => outcome,
+        (Ok(()), None) => RunOutcome::Halted,
+    };
+    RunReport {
+        outcome,
+        steps: env.steps() - steps_before,
+        bytes_written: env.bytes_written() - bytes_before,
+        elapsed: start.elapsed(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, Language, PointerPolicy, RunOptions};
+
+    #[test]
+    fn test_run_report() {
+        let language = Language::default();
+
+        let mut data = [0; 4];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let report = run_report(&compress(&parse("+++.", &language)), &mut env, RunLimits::default());
+        assert_eq!((report.outcome, report.steps, report.bytes_written), (RunOutcome::Halted, 2, 1));
+
+        let limits = RunLimits { max_steps: Some(100), ..RunLimits::default() };
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let report = run_report(&parse("+[]", &language), &mut env, limits);
+        assert_eq!((report.outcome, report.steps), (RunOutcome::StepLimit, 100));
+
+        let limits = RunLimits { timeout: Some(Duration::from_millis(1)), ..RunLimits::default() };
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        assert_eq!(run_report(&parse("+[]", &language), &mut env, limits).outcome, RunOutcome::Timeout);
+
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        assert_eq!(run_report(&parse("<", &language), &mut env, RunLimits::default()).outcome,
+                   RunOutcome::Error(RuntimeError::PointerUnderflow { index: -1, pc: 0, source_offset: None }));
+    }
+}
@@ -0,0 +1,183 @@
+//! Testing interactive programs against a script of what they write and read.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use crate::{run, Code, Environment, RunOptions, Runnable, RuntimeError};
+
+/// Step of a script: output the program must write, then input it's given once
+/// it waits for more.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Interaction<'s> {
+    /// Output written since the previous interaction's input was all read
+    pub output: &'s [u8],
+    /// Input given to the program
+    pub input: &'s [u8],
+}
+
+/// Errors occurring while running a script.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ScriptError {
+    /// Program wrote other output before the interaction. The interaction is past
+    /// the end of the script for output written after the last one.
+    UnexpectedOutput { interaction: usize, expected: Vec<u8>, actual: Vec<u8> },
+    /// Program ended before reading the input of the interaction.
+    EndedEarly { interaction: usize },
+    /// Program failed.
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::UnexpectedOutput { interaction, expected, actual } => write!(
+                f, "expected {:?} before interaction {} but got {:?}",
+                String::from_utf8_lossy(expected), interaction, String::from_utf8_lossy(actual),
+            ),
+            ScriptError::EndedEarly { interaction } => write!(f, "ended before reading input of interaction {}", interaction),
+            ScriptError::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Where the program is up to in the script, shared by its reader and writer
+struct Progress<'s> {
+    script: &'s [Interaction<'s>],
+    output: Vec<u8>,
+    // Index of the interaction whose input is read, and how much of it is
+    current: Option<(usize, usize)>,
+    error: Option<ScriptError>,
+}
+
+impl<'s> Progress<'s> {
+    fn next_interaction(&self) -> usize {
+        self.current.map_or(0, |(index, _)| index + 1)
+    }
+
+    /// Check output written since the last check is what the interaction expects
+    fn check_output(&mut self, interaction: usize) -> Result<(), ScriptError> {
+        let expected = self.script.get(interaction).map_or(&[][..], |interaction| interaction.output);
+        let actual = std::mem::take(&mut self.output);
+        if actual != expected {
+            return Err(ScriptError::UnexpectedOutput { interaction, expected: expected.to_vec(), actual });
+        }
+        Ok(())
+    }
+}
+
+struct ScriptReader<'s>(Rc<RefCell<Progress<'s>>>);
+
+impl<'s> Read for ScriptReader<'s> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut progress = self.0.borrow_mut();
+        loop {
+            if let Some((index, read)) = progress.current {
+                let input = progress.script[index].input;
+                if read < input.len() {
+                    let len = buf.len().min(input.len() - read);
+                    buf[..len].copy_from_slice(&input[read..read + len]);
+                    progress.current = Some((index, read + len));
+                    return Ok(len);
+                }
+            }
+
+            // Reading past all the input in the script is EOF
+            let next = progress.next_interaction();
+            if next == progress.script.len() {
+                return Ok(0);
+            }
+            if let Err(err) = progress.check_output(next) {
+                progress.error = Some(err);
+                return Err(io::Error::other("script failed"));
+            }
+            progress.current = Some((next, 0));
+        }
+    }
+}
+
+struct ScriptWriter<'s>(Rc<RefCell<Progress<'s>>>);
+
+impl<'s> Write for ScriptWriter<'s> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the code with the options over a fresh tape, giving it the input of each
+/// interaction of the script in turn as it reads and checking it writes the
+/// output of each before reading its input. The program must read the input of
+/// every interaction and write nothing after the output of the last one.
+pub fn run_scripted<O: Runnable>(code: &Code<O>, options: RunOptions, script: &[Interaction]) -> Result<(), ScriptError> {
+    let progress = Rc::new(RefCell::new(Progress { script, output: Vec::new(), current: None, error: None }));
+    let mut reader = ScriptReader(Rc::clone(&progress));
+    let mut writer = ScriptWriter(Rc::clone(&progress));
+    let mut data = vec![0u8; options.tape_size()];
+
+    let mut env = Environment::with_options(&mut data, &mut reader, &mut writer, options);
+    let result = run(code, &mut env);
+
+    let mut progress = progress.borrow_mut();
+    if let Some(err) = progress.error.take() {
+        return Err(err);
+    }
+    result.map_err(ScriptError::Runtime)?;
+
+    // Output since the last read is what comes before the next interaction
+    let next = progress.next_interaction();
+    progress.check_output(next)?;
+    match script.get(next) {
+        Some(interaction) if !interaction.input.is_empty() => Err(ScriptError::EndedEarly { interaction: next }),
+        Some(_) if next + 1 < script.len() => Err(ScriptError::EndedEarly { interaction: next + 1 }),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, parse, Language};
+
+    /// Prompt with `>`, then echo each line read until an empty one
+    const ECHO_BF: &str = "++++++[>++++++++++<-]>++ . <,---------- [ [++++++++++.,----------] ++++++++++ . [-] >.< ,----------]";
+
+    fn interaction<'s>(output: &'s str, input: &'s str) -> Interaction<'s> {
+        Interaction { output: output.as_bytes(), input: input.as_bytes() }
+    }
+
+    #[test]
+    fn test_run_scripted() {
+        let code = compress(&parse(ECHO_BF, &Language::default()));
+        let options = RunOptions::builder().tape_size(4).build();
+
+        let script = [interaction(">", "hi\n"), interaction("hi\n>", "bye\n"), interaction("bye\n>", "\n")];
+        assert_eq!(run_scripted(&code, options.clone(), &script), Ok(()));
+
+        let script = [interaction(">", "hi\n"), interaction("hello\n>", "\n")];
+        assert_eq!(run_scripted(&code, options.clone(), &script), Err(ScriptError::UnexpectedOutput {
+            interaction: 1,
+            expected: b"hello\n>".to_vec(),
+            actual: b"hi\n>".to_vec(),
+        }));
+
+        let script = [interaction(">", "\n"), interaction("", "more\n")];
+        assert_eq!(run_scripted(&code, options.clone(), &script), Err(ScriptError::EndedEarly { interaction: 1 }));
+
+        // Output after the last read must be scripted too
+        let code = compress(&parse(",+.", &Language::default()));
+        assert_eq!(run_scripted(&code, options.clone(), &[interaction("", "a"), interaction("b", "")]), Ok(()));
+        assert_eq!(run_scripted(&code, options, &[interaction("", "a")]), Err(ScriptError::UnexpectedOutput {
+            interaction: 1,
+            expected: Vec::new(),
+            actual: b"b".to_vec(),
+        }));
+    }
+}
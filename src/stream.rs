@@ -0,0 +1,103 @@
+//! Running code as an iterator of output bytes.
+
+use std::collections::VecDeque;
+use std::io::Read;
+
+use crate::{step, Code, Environment, Runnable, RuntimeError, Tape};
+
+/// Iterator running code until it puts the next byte, so output is yielded as
+/// it's produced. Putting characters writes to the environment's `Vec`, which
+/// is drained as it goes. Iteration ends with the program or at an error.
+pub struct OutputStream<'e, 'a, R, T: ?Sized + Tape, O> {
+    code: &'e Code<O>,
+    env: &'e mut Environment<'a, R, Vec<u8>, T>,
+    pending: VecDeque<u8>,
+    error: Option<RuntimeError>,
+    ended: bool,
+}
+
+impl<'e, 'a, R: Read, T: ?Sized + Tape, O: Runnable> OutputStream<'e, 'a, R, T, O> {
+    /// Stream output of running the code over the environment
+    pub fn new(code: &'e Code<O>, env: &'e mut Environment<'a, R, Vec<u8>, T>) -> Self {
+        OutputStream { code, env, pending: VecDeque::new(), error: None, ended: false }
+    }
+
+    /// Error which ended iteration, if any
+    pub fn error(&self) -> Option<RuntimeError> {
+        self.error
+    }
+
+    /// Environment the code runs over
+    pub fn env(&self) -> &Environment<'a, R, Vec<u8>, T> {
+        self.env
+    }
+}
+
+impl<'e, 'a, R: Read, T: ?Sized + Tape, O: Runnable> Iterator for OutputStream<'e, 'a, R, T, O> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            if let Some(byte) = self.pending.pop_front() {
+                return Some(byte);
+            }
+            if self.ended {
+                return None;
+            }
+
+            match step(self.code, self.env) {
+                Ok(true) => self.pending.extend(self.env.writer.drain(..)),
+                Ok(false) => self.ended = true,
+                Err(err) => {
+                    self.error = Some(err);
+                    self.ended = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, Language, PointerPolicy, RunOptions, HELLO_BF};
+
+
+    #[test]
+    fn test_output_stream() {
+        let code = compress(&parse(HELLO_BF, &Language::default()));
+
+        let mut data = [0; 16];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let mut stream = OutputStream::new(&code, &mut env);
+
+        // Runs only as far as the first byte
+        assert_eq!(stream.next(), Some(b'H'));
+        assert_eq!(stream.env().pc(), code.loops()[0].end + 3);
+
+        let rest: Vec<u8> = stream.by_ref().collect();
+        assert_eq!(rest, b"ello World!\n");
+        assert_eq!(stream.error(), None);
+        assert_eq!(output, b"");
+    }
+
+    #[test]
+    fn test_output_stream_error() {
+        let code = parse(".+.<.", &Language::default());
+
+        let mut data = [0; 1];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+        let mut env = Environment::with_options(&mut data, &mut input, &mut output, options);
+        let mut stream = OutputStream::new(&code, &mut env);
+
+        assert_eq!(stream.by_ref().collect::<Vec<u8>>(), [0, 1]);
+        assert_eq!(stream.error(), Some(RuntimeError::PointerUnderflow { index: -1, pc: 3, source_offset: None }));
+    }
+}
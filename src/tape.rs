@@ -0,0 +1,271 @@
+//! Storage of cells the pointer moves over.
+
+use std::collections::VecDeque;
+
+use crate::Cell;
+
+/// Cells indexed from zero, which may grow at either end
+pub trait Tape {
+    /// Type of cells
+    type Cell: Cell;
+
+    /// Number of cells
+    fn len(&self) -> usize;
+
+    /// Whether there are no cells
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Read the cell at the index. Panics if the index is out of bounds.
+    fn get(&self, index: usize) -> Self::Cell;
+
+    /// Write the cell at the index. Panics if the index is out of bounds.
+    fn set(&mut self, index: usize, value: Self::Cell);
+
+    /// Insert `n` zero cells before the first cell, shifting indices of the others.
+    /// Returns false if the tape can't grow.
+    fn grow_front(&mut self, _n: usize) -> bool {
+        false
+    }
+
+    /// Append `n` zero cells after the last cell. Returns false if the tape can't grow.
+    fn grow_back(&mut self, _n: usize) -> bool {
+        false
+    }
+
+    /// Write cells to any storage behind the tape, such as a mapped file
+    fn flush(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Fixed size tape
+impl<C: Cell> Tape for [C] {
+    type Cell = C;
+
+    fn len(&self) -> usize {
+        <[C]>::len(self)
+    }
+
+    fn get(&self, index: usize) -> C {
+        self[index]
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self[index] = value;
+    }
+}
+
+/// Tape growing in both directions, as if it were infinite. Growing to the front
+/// moves the origin, so cells are also addressable by their position relative to
+/// the first cell when the tape was made, which goes negative to the left of it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BiTape<C = u8> {
+    cells: Vec<C>,
+    origin: usize,
+}
+
+impl<C: Cell> BiTape<C> {
+    /// Make a tape of `len` zero cells, at least one
+    pub fn new(len: usize) -> Self {
+        BiTape { cells: vec![C::ZERO; len.max(1)], origin: 0 }
+    }
+
+    /// Index of the cell at position zero
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Read the cell at the position relative to the origin, if it has been grown to
+    pub fn at(&self, position: isize) -> Option<C> {
+        let index = (self.origin as isize).checked_add(position)?;
+        if index < 0 {
+            return None;
+        }
+        self.cells.get(index as usize).copied()
+    }
+
+    /// Position relative to the origin of the cell at the index
+    pub fn position(&self, index: usize) -> isize {
+        index as isize - self.origin as isize
+    }
+
+    /// All cells from the leftmost
+    pub fn cells(&self) -> &[C] {
+        &self.cells
+    }
+}
+
+impl<C: Cell> Tape for BiTape<C> {
+    type Cell = C;
+
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn get(&self, index: usize) -> C {
+        self.cells[index]
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self.cells[index] = value;
+    }
+
+    fn grow_front(&mut self, n: usize) -> bool {
+        self.cells.splice(0..0, std::iter::repeat_n(C::ZERO, n));
+        self.origin += n;
+        true
+    }
+
+    fn grow_back(&mut self, n: usize) -> bool {
+        self.cells.resize(self.cells.len() + n, C::ZERO);
+        true
+    }
+}
+
+/// Tape growing in both directions like `BiTape`, backed by a ring buffer so
+/// growing to the front doesn't move the cells already there.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DequeTape<C = u8> {
+    cells: VecDeque<C>,
+    origin: usize,
+}
+
+impl<C: Cell> DequeTape<C> {
+    /// Make a tape of `len` zero cells, at least one
+    pub fn new(len: usize) -> Self {
+        DequeTape { cells: std::iter::repeat_n(C::ZERO, len.max(1)).collect(), origin: 0 }
+    }
+
+    /// Index of the cell at position zero
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Read the cell at the position relative to the origin, if it has been grown to
+    pub fn at(&self, position: isize) -> Option<C> {
+        let index = (self.origin as isize).checked_add(position)?;
+        if index < 0 {
+            return None;
+        }
+        self.cells.get(index as usize).copied()
+    }
+
+    /// Position relative to the origin of the cell at the index
+    pub fn position(&self, index: usize) -> isize {
+        index as isize - self.origin as isize
+    }
+
+    /// All cells from the leftmost
+    pub fn cells(&self) -> impl Iterator<Item = C> + '_ {
+        self.cells.iter().copied()
+    }
+}
+
+impl<C: Cell> Tape for DequeTape<C> {
+    type Cell = C;
+
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn get(&self, index: usize) -> C {
+        self.cells[index]
+    }
+
+    fn set(&mut self, index: usize, value: C) {
+        self.cells[index] = value;
+    }
+
+    fn grow_front(&mut self, n: usize) -> bool {
+        self.cells.reserve(n);
+        for _ in 0..n {
+            self.cells.push_front(C::ZERO);
+        }
+        self.origin += n;
+        true
+    }
+
+    fn grow_back(&mut self, n: usize) -> bool {
+        self.cells.resize(self.cells.len() + n, C::ZERO);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, run, Environment, Language, PointerPolicy, RunOptions};
+
+    #[test]
+    fn test_bitape_grows_left() {
+        let ops = parse("<<<+", &Language::default());
+
+        let mut tape = BiTape::<u8>::new(4);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(env.pointer(), 0);
+        assert_eq!(env.read_data(), 1);
+
+        assert_eq!(tape.len(), 7);
+        assert_eq!(tape.origin(), 3);
+        assert_eq!(tape.at(-3), Some(1));
+        assert_eq!(tape.at(0), Some(0));
+        assert_eq!(tape.at(-4), None);
+        assert_eq!(tape.position(0), -3);
+    }
+
+    #[test]
+    fn test_bitape_grows_both_ways() {
+        let language = Language::default();
+        let ops = compress(&parse("+>>>>>>++<<<<<<<<<<+++", &language));
+
+        let mut tape = BiTape::<u8>::new(1);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        // Never out of bounds as the tape grows instead
+        let options = RunOptions::builder().pointer_policy(PointerPolicy::Error).build();
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, options);
+        run(&ops, &mut env).unwrap();
+
+        assert_eq!(tape.at(0), Some(1));
+        assert_eq!(tape.at(6), Some(2));
+        assert_eq!(tape.at(-4), Some(3));
+        assert_eq!(tape.cells().len(), 11);
+    }
+
+    #[test]
+    fn test_deque_tape_grows_left() {
+        let language = Language::default();
+        // Walk left a cell at a time, carrying the data along and adding one each step
+        let source = format!("+{}", "<[-]>[-<+>]<+".repeat(1000));
+        let ops = parse(&source, &language);
+
+        let mut tape = DequeTape::<u8>::new(1);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::with_tape(&mut tape, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(env.pointer(), 0);
+
+        assert_eq!(tape.len(), 1001);
+        assert_eq!(tape.origin(), 1000);
+        assert_eq!(tape.at(0), Some(0));
+        assert_eq!(tape.at(-1000), Some((1001 % 256) as u8));
+        assert_eq!(tape.at(-1001), None);
+        assert_eq!(tape.position(0), -1000);
+
+        // Same cells as growing a tape over a vector
+        let mut bitape = BiTape::<u8>::new(1);
+        let mut env = Environment::with_tape(&mut bitape, &mut input, &mut output, RunOptions::default());
+        run(&ops, &mut env).unwrap();
+        assert_eq!(tape.cells().collect::<Vec<_>>(), bitape.cells());
+    }
+}
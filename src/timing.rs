@@ -0,0 +1,103 @@
+//! Timing how long each kind of operation takes to run.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+use crate::{step, Code, Environment, Runnable, RuntimeError, Tape};
+
+/// Time spent running one kind of operation.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct OpTiming {
+    /// Number of times an operation of the kind ran
+    pub calls: u64,
+    /// Total wall time running them, in nanoseconds
+    pub nanos: u64,
+}
+
+/// Time spent running each kind of operation, named as its variant is without
+/// any count, such as `Add` for `Add(3)`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TimingProfile {
+    timings: BTreeMap<String, OpTiming>,
+}
+
+impl TimingProfile {
+    /// Timing of the kind of operation, if any ran
+    pub fn get(&self, kind: &str) -> Option<OpTiming> {
+        self.timings.get(kind).copied()
+    }
+
+    /// Timings of each kind of operation which ran, ordered by kind
+    pub fn iter(&self) -> impl Iterator<Item = (&str, OpTiming)> {
+        self.timings.iter().map(|(kind, &timing)| (kind.as_str(), timing))
+    }
+}
+
+/// Name of the operation's variant, dropping any fields
+fn kind_name<O: fmt::Debug>(op: &O) -> String {
+    let name = format!("{:?}", op);
+    match name.find(|c: char| !c.is_alphanumeric()) {
+        Some(index) => name[..index].to_owned(),
+        None => name,
+    }
+}
+
+/// Execute operations, timing each one by its kind
+pub fn run_timed<R, W, T, O>(code: &Code<O>, env: &mut Environment<R, W, T>) -> Result<TimingProfile, RuntimeError>
+    where R: Read, W: Write, T: ?Sized + Tape, O: Runnable + fmt::Debug
+{
+    // Kinds are named up front so naming isn't timed
+    let mut kinds: Vec<String> = Vec::new();
+    let kind_of_pc = code.ops.iter()
+        .map(|op| {
+            let name = kind_name(op);
+            kinds.iter().position(|kind| *kind == name).unwrap_or_else(|| {
+                kinds.push(name);
+                kinds.len() - 1
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut timings = vec![OpTiming::default(); kinds.len()];
+
+    while let Some(&kind) = kind_of_pc.get(env.pc()) {
+        let start = Instant::now();
+        step(code, env)?;
+        let timing = &mut timings[kind];
+        timing.calls += 1;
+        timing.nanos += start.elapsed().as_nanos() as u64;
+    }
+
+    let timings = kinds.into_iter()
+        .zip(timings)
+        .filter(|(_, timing)| timing.calls > 0)
+        .collect();
+    Ok(TimingProfile { timings })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::{compress, parse, Language};
+
+    #[test]
+    fn test_run_timed() {
+        let code = compress(&parse("+++[->++<]>.<[]", &Language::default()));
+
+        let mut data = [0; 2];
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let mut env = Environment::new(&mut data, &mut input, &mut output);
+        let profile = run_timed(&code, &mut env).unwrap();
+        assert_eq!(output, [6]);
+
+        let calls = profile.iter().map(|(kind, timing)| (kind, timing.calls)).collect::<Vec<_>>();
+        assert_eq!(calls, [
+            ("Add", 4), ("Back", 4), ("Forward", 4), ("LoopEnd", 3), ("LoopStart", 2), ("PutChar", 1), ("Sub", 3),
+        ]);
+        assert_eq!(profile.get("GetChar"), None);
+    }
+}
@@ -0,0 +1,95 @@
+use std::fs::{create_dir_all, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+mod common;
+use common::HELLO_BF;
+
+/// Directory for files of a test, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!("bfk-cli-{}-{}", name, std::process::id()));
+        create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+    }
+}
+
+fn bf(args: &[&Path]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bfk")).args(args).output().unwrap()
+}
+
+#[test]
+fn test_run_bytecode_file() {
+    let dir = TempDir::new("bytecode");
+    let source = dir.path("hello.bf");
+    let bytecode = dir.path("hello.bfc");
+    write(&source, HELLO_BF).unwrap();
+
+    let compiled = bf(&["compile".as_ref(), &source, "-o".as_ref(), &bytecode]);
+    assert!(compiled.status.success());
+
+    let from_source = bf(&[&source]);
+    let from_bytecode = bf(&[&bytecode]);
+    assert!(from_bytecode.status.success());
+    assert_eq!(from_bytecode.stdout, b"Hello World!\n");
+    assert_eq!(from_bytecode.stdout, from_source.stdout);
+    assert_eq!(bf(&["run".as_ref(), &bytecode]).stdout, from_source.stdout);
+
+    // Options only for source are rejected rather than ignored
+    let rejected = bf(&["--rle".as_ref(), &bytecode]);
+    assert_eq!(rejected.status.code(), Some(64));
+    assert!(rejected.stdout.is_empty());
+
+    let dot = bf(&["--dot".as_ref(), &bytecode]);
+    assert!(String::from_utf8(dot.stdout).unwrap().starts_with("digraph"));
+}
+
+#[test]
+fn test_stream_error_offsets() {
+    let dir = TempDir::new("stream");
+    let source = dir.path("underflow.bf");
+    write(&source, "+\n  <").unwrap();
+
+    let streamed = bf(&["--stream".as_ref(), "--pointer".as_ref(), "error".as_ref(), &source]);
+    assert_eq!(streamed.status.code(), Some(70));
+    assert!(String::from_utf8(streamed.stderr).unwrap().contains("(source offset 4)"));
+}
+
+#[test]
+fn test_stream_large_file() {
+    let dir = TempDir::new("stream-large");
+    let source = dir.path("hello.bf");
+    // Each copy clears the cells it used, so the next prints the same
+    write(&source, format!("{}\nclear the tape\n{}\n", HELLO_BF, "[-]<".repeat(6)).repeat(5000)).unwrap();
+
+    let streamed = bf(&["--stream".as_ref(), &source]);
+    let whole = bf(&[&source]);
+    assert!(whole.status.success());
+    assert_eq!(whole.stdout, b"Hello World!\n".repeat(5000));
+    assert_eq!(streamed.status.code(), whole.status.code());
+    assert_eq!(streamed.stdout, whole.stdout);
+}
+
+#[test]
+fn test_explain_without_compression() {
+    let dir = TempDir::new("explain");
+    let source = dir.path("hello.bf");
+    write(&source, HELLO_BF).unwrap();
+
+    // Nothing is optimized without compressing, so asking to explain it is an error
+    let rejected = bf(&["-n".as_ref(), "--explain".as_ref(), &source]);
+    assert!(!rejected.status.success());
+    assert!(rejected.stdout.is_empty());
+}
@@ -0,0 +1,28 @@
+use std::io::{Cursor, ErrorKind};
+
+use bfk::{parse_reader, parse_with_source_map, Language};
+
+mod common;
+use common::HELLO_BF;
+
+#[test]
+fn test_parse_reader() {
+    let language = Language::default().with_comment_delimiters('{', '}').unwrap().with_expectations();
+
+    // Comments and expectations spanning and starting lines are skipped the same way
+    let source = format!("{}\n{{ [ comment\n over lines ] }}\n-- expect: [\nλ.\n", HELLO_BF).repeat(1000);
+    let code = parse_reader(Cursor::new(source.as_bytes()), &language).unwrap();
+    assert_eq!(code, parse_with_source_map(&source, &language));
+    assert_eq!(code.source_map().unwrap().len(), HELLO_BF.len() * 1000 + 1000);
+
+    // Offsets count from the start of the source, not of each line
+    let code = parse_reader(Cursor::new(&b"+\n{ x }.\n"[..]), &language).unwrap();
+    assert_eq!(code.source_map(), Some(&[0, 7][..]));
+
+    let error = parse_reader(Cursor::new(&b"+\xff."[..]), &language).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+    let error = parse_reader(Cursor::new(&b"+\n]"[..]), &language).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "unmatched loop end at offset 2");
+}